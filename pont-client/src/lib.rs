@@ -7,6 +7,7 @@ use std::sync::Mutex;
 use web_sys::{
     AddEventListenerOptions,
     Blob,
+    BlobPropertyBag,
     Element,
     Event,
     EventTarget,
@@ -16,14 +17,36 @@ use web_sys::{
     HtmlButtonElement,
     HtmlElement,
     HtmlInputElement,
+    HtmlSelectElement,
     MessageEvent,
     PointerEvent,
     ProgressEvent,
+    Storage,
     SvgGraphicsElement,
+    Url,
     WebSocket,
+    XmlHttpRequest,
 };
 
-use pont_common::{ClientMessage, ServerMessage, Shape, Color, Piece, Game};
+use pont_common::{ClientMessage, ServerMessage, Difficulty, Shape, Color, Piece,
+                  Game, MoveError, PlayScore, RuleSet, Standing};
+use pont_common::board::BoardGrid;
+use pont_common::replay::Recording;
+
+// Time between turns when auto-playing a replay
+const REPLAY_STEP_MS: i32 = 1000;
+
+// How often the turn timer's countdown is redrawn
+const CLOCK_STEP_MS: i32 = 250;
+
+// Spectators don't have a seat, so their player index never matches anyone
+const NO_SEAT: usize = usize::MAX;
+
+// Formats a duration in milliseconds as minutes and seconds
+fn format_clock(ms: f64) -> String {
+    let secs = (ms.max(0.0) / 1000.0).ceil() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
 
 // Minimal logging macro
 macro_rules! console_log {
@@ -44,6 +67,30 @@ impl DocExt for Document {
     }
 }
 
+// Rejoin tokens are kept in local storage, keyed by room name, so that a
+// player can reclaim their seat after a disconnect or page reload.
+fn local_storage() -> Option<Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+fn token_key(room_name: &str) -> String {
+    format!("pont-token:{}", room_name)
+}
+
+fn stored_token(room_name: &str) -> Option<String> {
+    local_storage()?.get_item(&token_key(room_name)).ok()?
+}
+
+// Failing to save the token isn't fatal, but means that we can't
+// reclaim our seat if we get disconnected.
+fn save_token(room_name: &str, token: &str) {
+    if let Some(s) = local_storage() {
+        if let Err(e) = s.set_item(&token_key(room_name), token) {
+            console_log!("Could not save rejoin token: {:?}", e);
+        }
+    }
+}
+
 fn get_time_ms() -> f64 {
     web_sys::window()
         .expect("No global window found")
@@ -183,16 +230,26 @@ pub struct Board {
     pan_group: Element,
     pan_offset: Pos,
 
-    grid: HashMap<(i32, i32), Piece>,
+    grid: BoardGrid,
     tentative: HashMap<(i32, i32), usize>,
+    rules: RuleSet,
     exchange_list: Vec<usize>,
     pieces_remaining: usize,
+
+    // Horizontal spacing between pieces in the hand, which shrinks so that
+    // larger hands still fit in the hand area
+    hand_step: f32,
     hand: Vec<(Piece, Element)>,
 
     accept_button: HtmlButtonElement,
     reject_button: HtmlButtonElement,
     exchange_div: Element,
 
+    // Shows what the tentative play is worth, with the lines that score
+    // highlighted underneath the pieces
+    score_preview: HtmlElement,
+    score_group: Element,
+
     pointer_down_cb: JsClosure<PointerEvent>,
     pointer_move_cb: JsClosure<PointerEvent>,
     pointer_up_cb: JsClosure<PointerEvent>,
@@ -269,6 +326,11 @@ impl Board {
             .expect("Could not find pan_group");
         let exchange_div = doc.get_element_by_id("exchange_div")
             .expect("Could not find exchange_div");
+        let score_preview = doc.get_element_by_id("score_preview")
+            .expect("Could not find score_preview")
+            .dyn_into()?;
+        let score_group = doc.get_element_by_id("score_group")
+            .expect("Could not find score_group");
 
         let out = Board {
             doc: doc.clone(),
@@ -276,8 +338,9 @@ impl Board {
             svg, svg_div,
             pan_group,
             pan_offset: (0.0, 0.0),
-            grid: HashMap::new(),
+            grid: BoardGrid::new(),
             tentative: HashMap::new(),
+            rules: RuleSet::default(),
             exchange_list: Vec::new(),
             hand: Vec::new(),
             pointer_down_cb,
@@ -290,12 +353,27 @@ impl Board {
             accept_button,
             reject_button,
             exchange_div,
+            score_preview,
+            score_group,
             pieces_remaining: 0,
+            hand_step: 15.0,
         };
 
         Ok(out)
     }
 
+    fn set_rules(&mut self, rules: RuleSet) {
+        self.rules = rules;
+
+        // The hand area runs from x = 5 to 95, and each piece is 10 wide
+        self.hand_step = (80.0 / (rules.hand_size.max(2) - 1) as f32).min(15.0);
+    }
+
+    // Returns the resting position of the piece in the given hand slot
+    fn hand_pos(&self, i: usize) -> Pos {
+        (i as f32 * self.hand_step + 5.0, 185.0)
+    }
+
     fn set_my_turn(&mut self, is_my_turn: bool) -> JsError {
         if is_my_turn {
             self.svg_div.class_list().remove_1("nyt")?;
@@ -436,7 +514,7 @@ impl Board {
 
         let (hand_index, grid_origin) = if my > 185.0 {
             // Picking from hand
-            let i = (tx.round() as i32 - 5) / 15;
+            let i = ((tx - 5.0) / self.hand_step).round() as i32;
             self.svg.remove_child(&target)?;
             (i as usize, None)
         } else {
@@ -574,17 +652,10 @@ impl Board {
     }
 
     fn mark_invalid(&self) -> JsResult<bool> {
-        let mut b = self.grid.clone();
-        for (pos, index) in self.tentative.iter() {
-            b.insert(*pos, self.hand[*index].0);
-        }
-        let mut invalid = Game::invalid(&b);
-        let play = self.tentative.keys().cloned().collect::<Vec<_>>();
-        if !Game::is_linear_connected(&b, &play) {
-            for pos in self.tentative.keys() {
-                invalid.insert(*pos);
-            }
-        }
+        let play = self.tentative.iter()
+            .map(|((x, y), index)| (self.hand[*index].0, *x, *y))
+            .collect::<Vec<_>>();
+        let invalid = Game::invalid_play(&self.grid, &play);
         for (pos, index) in self.tentative.iter() {
             if invalid.contains(pos) {
                 self.hand[*index].1.class_list().add_1("invalid")?;
@@ -592,9 +663,58 @@ impl Board {
                 self.hand[*index].1.class_list().remove_1("invalid")?;
             }
         }
+
+        // Only plays that could be sent are worth scoring
+        if play.is_empty() || !invalid.is_empty() {
+            self.clear_score()?;
+        } else {
+            self.show_score(&Game::score_play(&self.grid, self.rules, &play))?;
+        }
         Ok(invalid.is_empty())
     }
 
+    fn show_score(&self, score: &PlayScore) -> JsError {
+        let total = score.breakdown.total();
+        let mut text = format!("+{}", total);
+        if score.breakdown.qwirkles > 0 {
+            text.push('!');
+        }
+        self.score_preview.set_text_content(Some(&text));
+        self.score_preview.set_title(&score.lines.iter()
+            .map(|line| if line.qwirkle {
+                format!("{} (Qwirkle)", line.points)
+            } else {
+                line.points.to_string()
+            })
+            .collect::<Vec<_>>()
+            .join(" + "));
+        self.score_preview.set_hidden(false);
+
+        // Draw a box around each line, which sits behind the pieces
+        self.score_group.set_inner_html("");
+        for line in score.lines.iter() {
+            let (x0, y0) = line.cells[0];
+            let (x1, y1) = line.cells[line.cells.len() - 1];
+            let r = self.doc.create_svg_element("rect")?;
+            r.set_attribute("x", &(x0 * 10 - 1).to_string())?;
+            r.set_attribute("y", &(y0 * 10 - 1).to_string())?;
+            r.set_attribute("width", &((x1 - x0 + 1) * 10 + 2).to_string())?;
+            r.set_attribute("height", &((y1 - y0 + 1) * 10 + 2).to_string())?;
+            r.class_list().add_1("score-line")?;
+            if line.qwirkle {
+                r.class_list().add_1("qwirkle")?;
+            }
+            self.score_group.append_child(&r)?;
+        }
+        Ok(())
+    }
+
+    fn clear_score(&self) -> JsError {
+        self.score_preview.set_hidden(true);
+        self.score_group.set_inner_html("");
+        Ok(())
+    }
+
     fn on_pointer_up(&mut self, evt: PointerEvent) -> JsError {
         if let BoardState::Dragging(d) = &self.state {
             evt.prevent_default();
@@ -618,7 +738,7 @@ impl Board {
                         TileAnimation {
                             target: d.target.clone(),
                             start: pos,
-                            end: ((d.hand_index * 15 + 5) as f32, 185.0),
+                            end: self.hand_pos(d.hand_index),
                             t0: evt.time_stamp()
                         })))
                 },
@@ -731,8 +851,8 @@ impl Board {
         let g = self.new_piece(p)?;
         self.svg.append_child(&g)?;
         g.class_list().add_1("piece")?;
-        g.set_attribute("transform", &format!("translate({} 185)",
-                                              5 + 15 * self.hand.len()))?;
+        let (x, y) = self.hand_pos(self.hand.len());
+        g.set_attribute("transform", &format!("translate({} {})", x, y))?;
 
         let mut options = AddEventListenerOptions::new();
         options.passive(false);
@@ -820,6 +940,11 @@ impl Board {
                 g.append_child(&s)?;
                 g
             }
+            Shape::Triangle => {
+                let s = self.doc.create_svg_element("polygon")?;
+                s.set_attribute("points", "5,1.5 8.5,8 1.5,8")?;
+                s
+            }
         };
         s.class_list().add_1("color")?;
 
@@ -832,11 +957,12 @@ impl Board {
             Color::Red => "shape-red",
             Color::Blue => "shape-blue",
             Color::Purple => "shape-purple",
+            Color::Pink => "shape-pink",
         })?;
 
         // Add carets on the corners based on color, to be accessible
         let mut pts = Vec::new();
-        if p.1 == Color::Orange || p.1 == Color::Yellow {
+        if p.1 == Color::Orange || p.1 == Color::Yellow || p.1 == Color::Pink {
             pts.push("0.5,0.5 3,0.5 0.5,3");
         }
         if p.1 == Color::Orange || p.1 == Color::Green {
@@ -845,7 +971,7 @@ impl Board {
         if p.1 == Color::Red || p.1 == Color::Blue {
             pts.push("0.5,9.5 3,9.5 0.5,7");
         }
-        if p.1 == Color::Red || p.1 == Color::Purple {
+        if p.1 == Color::Red || p.1 == Color::Purple || p.1 == Color::Pink {
             pts.push("9.5,0.5 7,0.5 9.5,3");
         }
 
@@ -873,6 +999,35 @@ impl Board {
         Ok(g)
     }
 
+    // Jumps any running animation to its final frame
+    fn finish_animation(&mut self) -> JsError {
+        if let BoardState::Animation(_) = &self.state {
+            self.on_anim(f64::INFINITY)?;
+        }
+        Ok(())
+    }
+
+    // Removes every piece from the board and hand, which is used when
+    // rebuilding the board while scrubbing through a replay.
+    fn reset(&mut self) -> JsError {
+        self.finish_animation()?;
+        if let BoardState::Panning(_) = &self.state {
+            self.on_pan_end(Event::new("CancelPan")?)?;
+        }
+        for (_, e) in self.hand.drain(0..) {
+            self.svg.remove_child(&e)?;
+        }
+        self.pan_group.set_inner_html("");
+        self.pan_group.append_child(&self.score_group)?;
+        self.clear_score()?;
+        self.grid.clear();
+        self.tentative.clear();
+        self.exchange_list.clear();
+
+        self.pan_offset = (0.0, 0.0);
+        self.pan_group.set_attribute("transform", "translate(0 0)")
+    }
+
     fn on_reject_button(&mut self, evt: Event) -> JsError {
         // Don't allow for any tricky business here
         if self.state != BoardState::Idle {
@@ -880,6 +1035,7 @@ impl Board {
         }
 
         let drag = if !self.tentative.is_empty() {
+            self.clear_score()?;
             let mut tiles = HashMap::new();
             std::mem::swap(&mut self.tentative, &mut tiles);
             // Take every active tile and free them from the tile grid,
@@ -901,7 +1057,7 @@ impl Board {
                         target: self.hand[i].1.clone(),
                         start: (tx as f32 * 10.0 + self.pan_offset.0,
                                 ty as f32 * 10.0 + self.pan_offset.1),
-                        end: ((i * 15 + 5) as f32, 185.0),
+                        end: self.hand_pos(i),
                         t0: evt.time_stamp()
                     }).collect())))
         } else if !self.exchange_list.is_empty() {
@@ -913,11 +1069,11 @@ impl Board {
                     .map(|i| {
                         let target = self.hand[i].1.clone();
                         target.set_attribute("visibility", "visible")?;
-                        let x = (i * 15 + 5) as f32;
+                        let (x, y) = self.hand_pos(i);
                         Ok(TileAnimation {
                             target,
                             start: (x, 200.0),
-                            end:   (x, 185.0),
+                            end:   (x, y),
                             t0: evt.time_stamp()
                         })
                     })
@@ -947,13 +1103,12 @@ impl Board {
         // but we'll let the server tell us that.
         self.accept_button.set_disabled(true);
         self.reject_button.set_disabled(true);
+        self.clear_score()?;
 
         self.set_my_turn(false)?;
 
         if !self.tentative.is_empty() {
-            Ok(Move::Place(self.tentative.iter()
-                .map(|((x, y), i)| (self.hand[*i].0, *x, *y))
-                .collect()))
+            Ok(Move::Place(self.tentative_play()))
         } else {
             assert!(!self.exchange_list.is_empty());
             Ok(Move::Swap(self.exchange_list.iter()
@@ -962,6 +1117,13 @@ impl Board {
         }
     }
 
+    // Returns the pieces staged on the grid, in the format of a Play message
+    fn tentative_play(&self) -> Vec<(Piece, i32, i32)> {
+        self.tentative.iter()
+            .map(|((x, y), i)| (self.hand[*i].0, *x, *y))
+            .collect()
+    }
+
     fn on_move_accepted(&mut self, dealt: &[Piece]) -> JsError {
         let mut placed = HashMap::new();
         for ((x, y), i) in self.tentative.drain() {
@@ -991,20 +1153,20 @@ impl Board {
                 if self.hand.len() != i {
                     anims.push(TileAnimation {
                         target: element.clone(),
-                        start: (i as f32 * 15.0 + 5.0, 185.0),
-                        end: (self.hand.len() as f32 * 15.0 + 5.0, 185.0),
+                        start: self.hand_pos(i),
+                        end: self.hand_pos(self.hand.len()),
                         t0 });
                 }
                 self.hand.push((piece, element));
             }
         }
         for d in dealt {
-            let x = self.hand.len() as f32 * 15.0 + 5.0;
+            let end = self.hand_pos(self.hand.len());
             let target = self.add_hand(*d)?;
             anims.push(TileAnimation {
                 target,
-                start: (x, 220.0),
-                end: (x, 185.0),
+                start: (end.0, 220.0),
+                end,
                 t0
             })
         }
@@ -1096,11 +1258,18 @@ struct CreateOrJoin {
     colorblind_checkbox: HtmlInputElement,
     err_div: HtmlElement,
     err_span: HtmlElement,
+    replay_file: HtmlInputElement,
+    replay_url: HtmlInputElement,
+
+    // Set when we're trying to reclaim a seat with a stored token
+    rejoining: bool,
 
     // Callbacks are owned so that it lives as long as the state
     _room_invalid_cb: JsClosure<Event>,
     _input_cb: JsClosure<Event>,
     _submit_cb: JsClosure<Event>,
+    _watch_cb: JsClosure<Event>,
+    _spectate_cb: JsClosure<Event>,
 }
 
 struct Playing {
@@ -1109,14 +1278,108 @@ struct Playing {
     chat_div: HtmlElement,
     chat_input: HtmlInputElement,
     score_table: HtmlElement,
+    results_div: HtmlElement,
+    results_rows: HtmlElement,
+    host_div: HtmlElement,
+    name_rejoin_checkbox: HtmlInputElement,
+    pass_button: HtmlButtonElement,
+    room_name: String,
     player_index: usize,
     active_player: usize,
     player_names: Vec<String>,
+    rules: RuleSet,
+
+    // Moderation state, which decides what the host's buttons in the
+    // score table do
+    host: usize,
+    muted: Vec<bool>,
+    kicked: Vec<bool>,
+
+    // Milliseconds left on each player's total clock, and the time (from
+    // get_time_ms) when the active player's turn runs out
+    clock_ms: Vec<u64>,
+    turn_deadline: Option<f64>,
+    clock_interval: Option<i32>,
 
     board: Board,
 
-    // Callback is owned so that it lives as long as the state
+    // Callbacks are owned so that they live as long as the state
     _keyup_cb: JsClosure<KeyboardEvent>,
+    _save_replay_cb: JsClosure<Event>,
+    _name_rejoin_cb: JsClosure<Event>,
+    _pass_cb: JsClosure<Event>,
+    _host_cbs: Vec<JsClosure<Event>>,
+    clock_cb: JsClosure<JsValue>,
+}
+
+// Watching a live game without a seat, which reuses the Playing UI (like a
+// replay) without any of the controls that would change the game.
+struct Spectating {
+    playing: Playing,
+}
+
+// Waiting in a room for the host to start the game.  This wraps the
+// Playing UI, so that chat and the score table work as usual.
+struct Lobby {
+    playing: Playing,
+
+    lobby_div: HtmlElement,
+    host_div: HtmlElement,
+    order_list: HtmlElement,
+    ready_checkbox: HtmlInputElement,
+    bot_select: HtmlSelectElement,
+
+    // House rules, which only the host can edit
+    kinds_input: HtmlInputElement,
+    hand_size_input: HtmlInputElement,
+    copies_input: HtmlInputElement,
+    qwirkle_bonus_input: HtmlInputElement,
+    end_bonus_input: HtmlInputElement,
+    turn_secs_input: HtmlInputElement,
+    clock_secs_input: HtmlInputElement,
+    increment_secs_input: HtmlInputElement,
+    strict_opening_checkbox: HtmlInputElement,
+    max_seats_input: HtmlInputElement,
+    lock_checkbox: HtmlInputElement,
+
+    host: usize,
+    ready: Vec<bool>,
+    order: Vec<usize>,
+
+    // Callbacks are owned so that they live as long as the state.  The
+    // turn order buttons are rebuilt whenever the list is redrawn.
+    _ready_cb: JsClosure<Event>,
+    _add_bot_cb: JsClosure<Event>,
+    _start_cb: JsClosure<Event>,
+    _kinds_cb: JsClosure<Event>,
+    _rules_cb: JsClosure<Event>,
+    _order_cbs: Vec<JsClosure<Event>>,
+}
+
+// Watching a recorded game, which reuses the Playing UI without any of
+// the controls that talk to the server.
+struct Watching {
+    playing: Playing,
+
+    // Messages from the recording, starting with JoinedRoom
+    messages: Vec<ServerMessage>,
+
+    // Each frame ends at the start of a player's turn, and is stored as
+    // the number of messages that have been applied by that point.
+    frames: Vec<usize>,
+    frame: usize,
+
+    scrub: HtmlInputElement,
+    play_button: HtmlButtonElement,
+    turn_span: HtmlElement,
+    interval: Option<i32>,
+
+    tick_cb: JsClosure<JsValue>,
+    _back_cb: JsClosure<Event>,
+    _play_cb: JsClosure<Event>,
+    _forward_cb: JsClosure<Event>,
+    _scrub_cb: JsClosure<Event>,
+    _exit_cb: JsClosure<Event>,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -1124,7 +1387,10 @@ struct Playing {
 enum State {
     Connecting(Connecting),
     CreateOrJoin(CreateOrJoin),
+    Lobby(Lobby),
     Playing(Playing),
+    Spectating(Spectating),
+    Watching(Watching),
     Empty,
 }
 
@@ -1135,10 +1401,23 @@ impl State {
         ],
         CreateOrJoin => [
             on_joined_room(room_name: &str, players: &[(String, u32, bool)],
-                           active_player: usize,
+                           rules: RuleSet,
                            player_index: usize,
                            board: &[((i32, i32), Piece)],
                            pieces: &[Piece]) -> Playing,
+            on_joined_lobby(room_name: &str,
+                            players: &[(String, u32, bool)],
+                            rules: RuleSet,
+                            player_index: usize) -> Lobby,
+            on_spectating(room_name: &str,
+                          players: &[(String, u32, bool)],
+                          rules: RuleSet,
+                          active_player: Option<usize>,
+                          board: &[((i32, i32), Piece)]) -> Spectating,
+            on_watch_replay(recording: Recording) -> Watching,
+        ],
+        Lobby => [
+            on_game_started(pieces: &[Piece]) -> Playing,
         ],
     );
 
@@ -1147,40 +1426,119 @@ impl State {
             on_pointer_down(evt: PointerEvent),
             on_pointer_up(evt: PointerEvent),
             on_pointer_move(evt: PointerEvent),
-            on_pan_start(evt: PointerEvent),
-            on_pan_move(evt: PointerEvent),
-            on_pan_end(evt: Event),
             on_accept_button(evt: Event),
             on_reject_button(evt: Event),
-            on_anim(t: f64),
-            on_send_chat(),
-            on_chat(from: &str, msg: &str),
-            on_information(msg: &str),
-            on_new_player(name: &str),
-            on_player_disconnected(index: usize),
-            on_player_reconnected(index: usize),
+            on_pass_button(),
             on_player_turn(active_player: usize),
             on_played(pieces: &[(Piece, i32, i32)]),
             on_swapped(count: usize),
             on_move_accepted(dealt: &[Piece]),
-            on_move_rejected(),
-            on_pieces_remaining(remaining: usize),
+            on_move_rejected(err: MoveError),
+            on_turn_time(ms: u64),
             on_player_score(delta: u32, total: u32),
-            on_finished(winner: usize),
+            on_finished(standings: &[Standing]),
+        ],
+        Lobby => [
+            on_ready_changed(),
+            on_add_bot(),
+            on_start_button(),
+            on_kinds_changed(),
+            on_rules_input(),
+            on_move_up(pos: usize),
+            on_player_ready(index: usize, ready: bool),
+            on_rules_changed(rules: RuleSet),
+            on_turn_order(order: &[usize]),
         ],
         CreateOrJoin => [
             on_room_name_invalid(),
             on_join_inputs_changed(),
             on_join_button(),
+            on_spectate_button(),
             on_join_failed(room: &str),
+            on_watch_button(),
+        ],
+        Watching => [
+            on_replay_back(),
+            on_replay_forward(),
+            on_replay_play(),
+            on_replay_scrub(),
+            on_replay_tick(),
+            on_replay_exit(),
         ],
     );
+
+    // Panning and animation are shared between playing and watching a
+    // game, so they're dispatched to the board directly.
+    fn board(&mut self) -> &mut Board {
+        match self {
+            State::Playing(s) => &mut s.board,
+            State::Spectating(s) => &mut s.playing.board,
+            State::Watching(s) => &mut s.playing.board,
+            _ => panic!("Invalid state transition"),
+        }
+    }
+
+    // Chat and the score table work the same way in the lobby as in the
+    // game, so they're dispatched to the Playing UI directly.
+    fn playing(&mut self) -> &mut Playing {
+        match self {
+            State::Playing(s) => s,
+            State::Lobby(s) => &mut s.playing,
+            State::Spectating(s) => &mut s.playing,
+            _ => panic!("Invalid state transition"),
+        }
+    }
+
+    fn on_clock_tick(&mut self) -> JsError {
+        self.playing().on_clock_tick()
+    }
+
+    // The lobby also keeps its own list of players and the host
+    fn on_new_player(&mut self, name: &str) -> JsError {
+        match self {
+            State::Lobby(s) => s.on_new_player(name),
+            s => s.playing().on_new_player(name),
+        }
+    }
+
+    fn on_new_host(&mut self, index: usize) -> JsError {
+        match self {
+            State::Lobby(s) => s.on_new_host(index),
+            s => s.playing().on_new_host(index),
+        }
+    }
+
+    fn on_player_kicked(&mut self, index: usize) -> JsError {
+        match self {
+            State::Lobby(s) => s.on_player_kicked(index),
+            s => s.playing().on_player_kicked(index),
+        }
+    }
+
+    fn on_anim(&mut self, t: f64) -> JsError {
+        self.board().on_anim(t)
+    }
+
+    fn on_pan_start(&mut self, evt: PointerEvent) -> JsError {
+        self.board().on_pan_start(evt)
+    }
+
+    fn on_pan_move(&mut self, evt: PointerEvent) -> JsError {
+        self.board().on_pan_move(evt)
+    }
+
+    fn on_pan_end(&mut self, evt: Event) -> JsError {
+        self.board().on_pan_end(evt)
+    }
 }
 
 unsafe impl Send for State { /* YOLO */}
 
 lazy_static::lazy_static! {
     static ref HANDLE: Mutex<State> = Mutex::new(State::Empty);
+
+    // Every message received from the server, so that it can be saved
+    static ref RECORDING: Mutex<Recording> = Mutex::new(Recording::new());
 }
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -1255,17 +1613,31 @@ impl CreateOrJoin {
             .dyn_into::<HtmlButtonElement>()?;
 
         play_button.set_text_content(Some(
-            if room_input.value().is_empty() {
-                "Create new room"
-            } else {
-                "Join existing room"
-            }));
+            Self::play_button_text(&room_input.value())));
         play_button.class_list().remove_1("disabled")?;
 
         let colorblind_checkbox = base.doc.get_element_by_id("colorblind")
             .expect("Could not find colorblind checkbox")
             .dyn_into()?;
 
+        let replay_file = base.doc.get_element_by_id("replay_file")
+            .expect("Could not find replay_file")
+            .dyn_into()?;
+        let replay_url = base.doc.get_element_by_id("replay_url")
+            .expect("Could not find replay_url")
+            .dyn_into()?;
+        let watch_button = base.doc.get_element_by_id("watch_button")
+            .expect("Could not find watch_button");
+        let watch_cb = set_event_cb(&watch_button, "click", move |_: Event| {
+            HANDLE.lock().unwrap().on_watch_button()
+        });
+        let spectate_button = base.doc.get_element_by_id("spectate_button")
+            .expect("Could not find spectate_button");
+        let spectate_cb = set_event_cb(&spectate_button, "click",
+            move |_: Event| {
+                HANDLE.lock().unwrap().on_spectate_button()
+            });
+
         Ok(CreateOrJoin {
             base,
             name_input,
@@ -1274,24 +1646,39 @@ impl CreateOrJoin {
             colorblind_checkbox,
             err_div,
             err_span,
+            replay_file,
+            replay_url,
+            rejoining: false,
 
             _input_cb: input_cb,
             _submit_cb: submit_cb,
             _room_invalid_cb: room_invalid_cb,
+            _watch_cb: watch_cb,
+            _spectate_cb: spectate_cb,
         })
     }
 
-    fn on_join_failed(&self, err: &str) -> JsError {
+    fn on_join_failed(&mut self, err: &str) -> JsError {
+        // If we couldn't reclaim our seat, then forget the token, so that
+        // the next attempt joins as a new player.
+        if self.rejoining {
+            self.rejoining = false;
+            if let Some(s) = local_storage() {
+                s.remove_item(&token_key(&self.room_input.value()))?;
+            }
+            self.on_join_inputs_changed()?;
+        }
         self.err_span.set_text_content(Some(err));
         self.err_div.set_hidden(false);
         self.play_button.set_disabled(false);
         Ok(())
     }
 
-    fn on_joined_room(self, room_name: &str, players: &[(String, u32, bool)],
-                      active_player: usize, player_index: usize,
-                      board: &[((i32, i32), Piece)],
-                      pieces: &[Piece]) -> JsResult<Playing>
+    // Switches from the join form to the game UI
+    fn enter_room(self, room_name: &str, players: &[(String, u32, bool)],
+                  rules: RuleSet, player_index: usize,
+                  board: &[((i32, i32), Piece)],
+                  pieces: &[Piece]) -> JsResult<Playing>
     {
         self.base.doc.get_element_by_id("join")
             .expect("Could not get join div")
@@ -1302,39 +1689,160 @@ impl CreateOrJoin {
             .dyn_into::<HtmlElement>()?
             .set_hidden(false);
 
-        let mut p = Playing::new(self.base, room_name, players,
-                                 active_player, player_index,
+        let p = Playing::new(self.base, room_name, players,
+                                 rules, player_index,
                                  board, pieces)?;
         p.on_information(&format!("Welcome, {}!", players[player_index].0))?;
-        p.on_player_turn(active_player)?;
         Ok(p)
     }
 
-    fn on_join_button(&self) -> JsError {
-        self.play_button.set_disabled(true);
-        let name = self.name_input.value();
-        let room = self.room_input.value();
+    fn on_joined_room(self, room_name: &str, players: &[(String, u32, bool)],
+                      rules: RuleSet, player_index: usize,
+                      board: &[((i32, i32), Piece)],
+                      pieces: &[Piece]) -> JsResult<Playing>
+    {
+        let p = self.enter_room(room_name, players, rules, player_index,
+                                board, pieces)?;
+        p.show_rules()?;
+        Ok(p)
+    }
+
+    fn on_joined_lobby(self, room_name: &str,
+                       players: &[(String, u32, bool)],
+                       rules: RuleSet, player_index: usize)
+        -> JsResult<Lobby>
+    {
+        let p = self.enter_room(room_name, players, rules, player_index,
+                                &[], &[])?;
+        p.on_information("Waiting for the host to start the game")?;
+        Lobby::new(p)
+    }
+
+    fn on_spectating(self, room_name: &str, players: &[(String, u32, bool)],
+                     rules: RuleSet, active_player: Option<usize>,
+                     board: &[((i32, i32), Piece)]) -> JsResult<Spectating>
+    {
+        self.base.doc.get_element_by_id("join")
+            .expect("Could not get join div")
+            .dyn_into::<HtmlElement>()?
+            .set_hidden(true);
+        let playing = self.base.doc.get_element_by_id("playing")
+            .expect("Could not get playing div")
+            .dyn_into::<HtmlElement>()?;
+        playing.class_list().add_1("spectating")?;
+        playing.set_hidden(false);
+
+        let mut p = Playing::new(self.base, room_name, players, rules,
+                                 NO_SEAT, board, &[])?;
+        p.on_information("You are spectating this room")?;
+        p.show_rules()?;
+        if let Some(a) = active_player {
+            p.on_player_turn(a)?;
+        }
+        Ok(Spectating { playing: p })
+    }
+
+    fn on_watch_replay(self, recording: Recording) -> JsResult<Watching> {
+        self.base.doc.get_element_by_id("join")
+            .expect("Could not get join div")
+            .dyn_into::<HtmlElement>()?
+            .set_hidden(true);
+        let playing = self.base.doc.get_element_by_id("playing")
+            .expect("Could not get playing div")
+            .dyn_into::<HtmlElement>()?;
+        playing.class_list().add_1("replay")?;
+        self.set_colorblind()?;
+        playing.set_hidden(false);
+
+        Watching::new(self.base, recording)
+    }
+
+    fn on_watch_button(&mut self) -> JsError {
+        // The loading callbacks only run once, so they're leaked rather
+        // than stored, since the state changes while they're running.
+        let file = self.replay_file.files().and_then(|f| f.get(0));
+        let url = self.replay_url.value();
+        if let Some(file) = file {
+            let reader = FileReader::new()?;
+            set_event_cb(&reader, "load", move |e: ProgressEvent| {
+                let reader: FileReader = e.target()
+                    .expect("Could not get target")
+                    .dyn_into()?;
+                load_replay(&reader.result()?.as_string().unwrap_or_default())
+            }).forget();
+            reader.read_as_text(&file)
+        } else if !url.is_empty() {
+            let xhr = XmlHttpRequest::new()?;
+            set_event_cb(&xhr, "load", move |e: ProgressEvent| {
+                let xhr: XmlHttpRequest = e.target()
+                    .expect("Could not get target")
+                    .dyn_into()?;
+                match xhr.status()? {
+                    200 => load_replay(&xhr.response_text()?.unwrap_or_default()),
+                    s => HANDLE.lock().unwrap().on_join_failed(
+                        &format!("Could not download replay (error {})", s)),
+                }
+            }).forget();
+            set_event_cb(&xhr, "error", move |_: ProgressEvent| {
+                HANDLE.lock().unwrap().on_join_failed(
+                    "Could not download replay")
+            }).forget();
+            xhr.open("GET", &url)?;
+            xhr.send()
+        } else {
+            self.on_join_failed("Choose a replay file or URL")
+        }
+    }
+
+    fn set_colorblind(&self) -> JsError {
         if self.colorblind_checkbox.checked() {
             self.base.doc.get_element_by_id("playing")
                 .ok_or_else(|| JsValue::from_str("No playing box"))?
                 .class_list()
                 .add_1("colorblind")?;
         }
+        Ok(())
+    }
+
+    fn on_join_button(&mut self) -> JsError {
+        self.play_button.set_disabled(true);
+        let name = self.name_input.value();
+        let room = self.room_input.value();
+        self.set_colorblind()?;
         let msg = if room.is_empty() {
             ClientMessage::CreateRoom(name)
+        } else if let Some(token) = stored_token(&room) {
+            self.rejoining = true;
+            ClientMessage::Rejoin(room, token)
         } else {
             ClientMessage::JoinRoom(name, room)
         };
         self.base.send(msg)
     }
 
+    fn on_spectate_button(&mut self) -> JsError {
+        let room = self.room_input.value();
+        if room.is_empty() || !self.room_input.check_validity() {
+            return self.on_join_failed("Enter a room to spectate");
+        }
+        self.play_button.set_disabled(true);
+        self.set_colorblind()?;
+        self.base.send(ClientMessage::Spectate(room))
+    }
+
+    fn play_button_text(room_name: &str) -> &'static str {
+        if room_name.is_empty() {
+            "Create new room"
+        } else if stored_token(room_name).is_some() {
+            "Rejoin room"
+        } else {
+            "Join existing room"
+        }
+    }
+
     fn on_join_inputs_changed(&self) -> JsError {
         self.play_button.set_text_content(Some(
-            if self.room_input.value().is_empty() {
-                "Create new room"
-            } else {
-                "Join existing room"
-            }));
+            Self::play_button_text(&self.room_input.value())));
         self.room_input.set_custom_validity("");
         Ok(())
     }
@@ -1349,7 +1857,7 @@ impl CreateOrJoin {
 
 impl Playing {
     fn new(base: Base, room_name: &str, players: &[(String, u32, bool)],
-           active_player: usize, player_index: usize,
+           rules: RuleSet, player_index: usize,
            in_board: &[((i32, i32), Piece)],
            pieces: &[Piece]) -> JsResult<Playing>
     {
@@ -1359,11 +1867,14 @@ impl Playing {
             .dyn_into()?;
         s.set_text_content(Some(&room_name));
 
-        let board = Board::new(&base.doc)?;
+        let mut board = Board::new(&base.doc)?;
+        board.set_rules(rules);
 
-        let b = base.doc.get_element_by_id("chat_name")
-            .expect("Could not get chat_name");
-        b.set_text_content(Some(&format!("{}:", players[player_index].0)));
+        if let Some((name, _, _)) = players.get(player_index) {
+            base.doc.get_element_by_id("chat_name")
+                .expect("Could not get chat_name")
+                .set_text_content(Some(&format!("{}:", name)));
+        }
 
         // If Enter is pressed while focus is in the chat box,
         // send a chat message to the server.
@@ -1374,7 +1885,7 @@ impl Playing {
             move |e: KeyboardEvent| {
                 if e.key_code() == 13 { // Enter key
                     e.prevent_default();
-                    HANDLE.lock().unwrap().on_send_chat()
+                    HANDLE.lock().unwrap().playing().on_send_chat()
                 } else {
                     Ok(())
                 }
@@ -1386,6 +1897,48 @@ impl Playing {
         let score_table = base.doc.get_element_by_id("score_rows")
             .expect("Could not get score_rows")
             .dyn_into()?;
+        let results_div = base.doc.get_element_by_id("results_div")
+            .expect("Could not get results_div")
+            .dyn_into()?;
+        let results_rows = base.doc.get_element_by_id("results_rows")
+            .expect("Could not get results_rows")
+            .dyn_into()?;
+
+        let save_replay_button = base.doc.get_element_by_id("save_replay_button")
+            .expect("Could not get save_replay_button");
+        let save_replay_cb = set_event_cb(&save_replay_button, "click",
+            move |_: Event| {
+                HANDLE.lock().unwrap().playing().on_save_replay()
+            });
+
+        let host_div = base.doc.get_element_by_id("host_div")
+            .expect("Could not get host_div")
+            .dyn_into()?;
+        let name_rejoin_checkbox = base.doc
+            .get_element_by_id("name_rejoin_checkbox")
+            .expect("Could not get name_rejoin_checkbox")
+            .dyn_into()?;
+        let name_rejoin_cb = set_event_cb(&name_rejoin_checkbox, "change",
+            move |_: Event| {
+                HANDLE.lock().unwrap().playing().on_name_rejoin_changed()
+            });
+
+        let pass_button = base.doc.get_element_by_id("pass_button")
+            .expect("Could not get pass_button")
+            .dyn_into()?;
+        let pass_cb = set_event_cb(&pass_button, "click", move |_: Event| {
+            HANDLE.lock().unwrap().on_pass_button()
+        });
+
+        // The score table only shows a clock column in timed games
+        let clock_cb = build_cb(move |_: JsValue| {
+            HANDLE.lock().unwrap().on_clock_tick()
+        });
+        if rules.is_timed() {
+            base.doc.get_element_by_id("score_table")
+                .expect("Could not get score_table")
+                .class_list().add_1("timed")?;
+        }
 
         let mut out = Playing {
             base,
@@ -1394,30 +1947,78 @@ impl Playing {
             chat_input,
             chat_div,
             score_table,
+            results_div,
+            results_rows,
+            host_div,
+            name_rejoin_checkbox,
+            pass_button,
+            room_name: room_name.to_string(),
             player_index,
-            active_player,
+            active_player: 0,
             player_names: Vec::new(),
+            rules,
+            host: 0,
+            muted: Vec::new(),
+            kicked: Vec::new(),
+            clock_ms: Vec::new(),
+            turn_deadline: None,
+            clock_interval: None,
 
             _keyup_cb: keyup_cb,
+            _save_replay_cb: save_replay_cb,
+            _name_rejoin_cb: name_rejoin_cb,
+            _pass_cb: pass_cb,
+            _host_cbs: Vec::new(),
+            clock_cb,
         };
+        out.load(players, in_board, pieces)?;
+        Ok(out)
+    }
 
+    // Fills in the board, hand, and score table
+    fn load(&mut self, players: &[(String, u32, bool)],
+            in_board: &[((i32, i32), Piece)], pieces: &[Piece]) -> JsError
+    {
         for ((x, y), p) in in_board.iter() {
-            out.board.add_piece(*p, *x, *y)?;
+            self.board.add_piece(*p, *x, *y)?;
         }
         for p in pieces.iter() {
-            out.board.add_hand(*p)?;
+            self.board.add_hand(*p)?;
         }
 
         for (i, (name, score, connected)) in players.iter().enumerate() {
-            out.add_player_row(
-                if i == player_index {
+            self.add_player_row(
+                if i == self.player_index {
                     format!("{} (you)", name)
                 } else {
                     name.to_string()
                 },
                 *score as usize, *connected)?;
         }
-        Ok(out)
+        Ok(())
+    }
+
+    // Clears the board, score table, and chat, undoing load()
+    fn reset(&mut self) -> JsError {
+        self.board.reset()?;
+
+        // The first three nodes are the table's header row and whitespace
+        while self.score_table.child_nodes().length() > 3 {
+            let row = self.score_table.last_child()
+                .expect("Could not get table row");
+            self.score_table.remove_child(&row)?;
+        }
+        self.player_names.clear();
+        self.muted.clear();
+        self.kicked.clear();
+        self._host_cbs.clear();
+        self.host = 0;
+        self.results_div.set_hidden(true);
+        self.clock_ms.clear();
+        self.turn_deadline = None;
+        self.active_player = 0;
+        self.chat_div.set_inner_html("");
+        Ok(())
     }
 
     fn on_chat(&self, from: &str, msg: &str) -> JsError {
@@ -1473,16 +2074,102 @@ impl Playing {
         td.set_text_content(Some(&score.to_string()));
         tr.append_child(&td)?;
 
+        let td = self.base.doc.create_element("td")?;
+        td.set_class_name("clock");
+        tr.append_child(&td)?;
+
+        let td = self.base.doc.create_element("td")?;
+        td.set_class_name("host-controls");
+        tr.append_child(&td)?;
+
         if !connected {
             tr.class_list().add_1("disconnected")?;
         }
 
         self.score_table.append_child(&tr)?;
         self.player_names.push(name);
+        self.muted.push(false);
+        self.kicked.push(false);
+        self.clock_ms.push(self.rules.clock_secs as u64 * 1000);
+        self.draw_clocks()?;
+        self.draw_host_controls()
+    }
 
+    // The host gets buttons next to everyone else's name, to mute them,
+    // kick them, or hand over the host role
+    fn draw_host_controls(&mut self) -> JsError {
+        self._host_cbs.clear();
+        let is_host = self.host == self.player_index;
+        for i in 0..self.player_names.len() {
+            let td = self.score_table.child_nodes()
+                .item((i + 3) as u32)
+                .unwrap()
+                .dyn_into::<HtmlElement>()?
+                .query_selector("td.host-controls")?
+                .expect("Could not get host controls");
+            td.set_inner_html("");
+            if !is_host || i == self.player_index || self.kicked[i] {
+                continue;
+            }
+
+            type Action = Box<dyn Fn(&Playing) -> JsError>;
+            let muted = self.muted[i];
+            let buttons: Vec<(&str, &str, Action)> = vec![
+                (if muted { "fa-comment" } else { "fa-comment-slash" },
+                 if muted { "Unmute" } else { "Mute" },
+                 Box::new(move |p| p.base.send(
+                     ClientMessage::Mute(i, !muted)))),
+                ("fa-crown", "Make host",
+                 Box::new(move |p| p.base.send(
+                     ClientMessage::TransferHost(i)))),
+                ("fa-user-slash", "Kick", Box::new(move |p| p.on_kick(i))),
+            ];
+            for (icon, title, f) in buttons.into_iter() {
+                let b = self.base.doc.create_element("button")?;
+                b.set_attribute("title", title)?;
+                b.set_inner_html(&format!("<i class=\"fas {}\"></i>", icon));
+                self._host_cbs.push(set_event_cb(&b, "click",
+                    move |_: Event| f(HANDLE.lock().unwrap().playing())));
+                td.append_child(&b)?;
+            }
+        }
         Ok(())
     }
 
+    fn on_kick(&self, index: usize) -> JsError {
+        let msg = format!("Remove {} from the room?", self.player_names[index]);
+        if self.base.doc.default_view()
+            .expect("Could not get window")
+            .confirm_with_message(&msg)?
+        {
+            self.base.send(ClientMessage::Kick(index))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_player_muted(&mut self, index: usize, muted: bool) -> JsError {
+        self.muted[index] = muted;
+        self.draw_host_controls()?;
+        let name = &self.player_names[index];
+        self.on_information(&match (index == self.player_index, muted) {
+            (true, true) => "The host muted you".to_string(),
+            (true, false) => "The host unmuted you".to_string(),
+            (false, true) => format!("{} was muted", name),
+            (false, false) => format!("{} was unmuted", name),
+        })
+    }
+
+    fn on_player_kicked(&mut self, index: usize) -> JsError {
+        self.kicked[index] = true;
+        self.draw_host_controls()?;
+        self.on_information(&if index == self.player_index {
+            "The host removed you from the room".to_string()
+        } else {
+            format!("{} was removed from the room", self.player_names[index])
+        })
+    }
+
     fn on_send_chat(&self) -> JsError {
         let i = self.chat_input.value();
         if !i.is_empty() {
@@ -1493,6 +2180,84 @@ impl Playing {
         }
     }
 
+    fn on_save_replay(&self) -> JsError {
+        let recording = RECORDING.lock().unwrap();
+        if recording.board() != self.board.grid {
+            console_log!("Warning: replay doesn't rebuild the current board");
+        }
+        let json = recording.to_json()
+            .map_err(|e| JsValue::from_str(
+                    &format!("Could not encode replay: {}", e)))?;
+
+        let mut options = BlobPropertyBag::new();
+        options.type_("application/json");
+        let blob = Blob::new_with_str_sequence_and_options(
+            &js_sys::Array::of1(&JsValue::from_str(&json)), &options)?;
+        let url = Url::create_object_url_with_blob(&blob)?;
+
+        // Download the file by clicking on a temporary link
+        let a = self.base.doc.create_element("a")?
+            .dyn_into::<HtmlElement>()?;
+        a.set_attribute("href", &url)?;
+        a.set_attribute("download",
+                        &format!("{}.json", self.room_name.replace(' ', "-")))?;
+        a.click();
+        Url::revoke_object_url(&url)
+    }
+
+    // Describes any rules that differ from the standard game
+    fn show_rules(&self) -> JsError {
+        let rules = self.rules;
+        if rules != RuleSet::default() {
+            self.on_information(&format!(
+                "House rules: {}x{} tiles, {} pieces per hand, \
+                 {} of each piece, {} point Qwirkle bonus, \
+                 {} point bonus for going out",
+                rules.kinds, rules.kinds, rules.hand_size, rules.copies,
+                rules.qwirkle_bonus, rules.end_bonus))?;
+        }
+        if rules.strict_opening {
+            self.on_information("The first play must be the longest line \
+                                 in that player's hand")?;
+        }
+        if rules.turn_secs > 0 {
+            self.on_information(&format!(
+                "Each turn is limited to {} seconds", rules.turn_secs))?;
+        }
+        if rules.clock_secs > 0 {
+            self.on_information(&format!(
+                "Each player has {} on their clock, plus {} seconds per turn",
+                format_clock(rules.clock_secs as f64 * 1000.0),
+                rules.increment_secs))?;
+        }
+        Ok(())
+    }
+
+    // The host can change the rules until the game starts
+    fn on_rules_changed(&mut self, rules: RuleSet) -> JsError {
+        self.rules = rules;
+        self.board.set_rules(rules);
+        self.base.doc.get_element_by_id("score_table")
+            .expect("Could not get score_table")
+            .class_list().toggle_with_force("timed", rules.is_timed())?;
+        for c in self.clock_ms.iter_mut() {
+            *c = rules.clock_secs as u64 * 1000;
+        }
+        self.draw_clocks()
+    }
+
+    // Only the host gets to decide how seats are reclaimed
+    fn on_new_host(&mut self, index: usize) -> JsError {
+        self.host = index;
+        self.host_div.set_hidden(index != self.player_index);
+        self.draw_host_controls()
+    }
+
+    fn on_name_rejoin_changed(&self) -> JsError {
+        self.base.send(ClientMessage::AllowNameRejoin(
+            self.name_rejoin_checkbox.checked()))
+    }
+
     fn on_new_player(&mut self, name: &str) -> JsError {
         // Append a player to the bottom of the scores list
         self.add_player_row(name.to_string(), 0, true)?;
@@ -1505,8 +2270,12 @@ impl Playing {
             .unwrap()
             .dyn_into::<HtmlElement>()?;
         c.class_list().add_1("disconnected")?;
-        self.on_information(&format!("{} disconnected",
-                                     self.player_names[index]))
+        if self.kicked[index] {
+            Ok(())  // Already announced
+        } else {
+            self.on_information(&format!("{} disconnected",
+                                         self.player_names[index]))
+        }
     }
 
     fn on_player_reconnected(&self, index: usize) -> JsError {
@@ -1530,7 +2299,10 @@ impl Playing {
             .class_list()
             .remove_1("active")?;
 
+        // The server follows up with the new turn's time limit
+        self.turn_deadline = None;
         self.active_player = active_player;
+        self.draw_clocks()?;
         children
             .item((self.active_player + 3) as u32)
             .unwrap()
@@ -1546,23 +2318,27 @@ impl Playing {
                          self.player_names[self.active_player]))
         }?;
 
+        self.pass_button.set_disabled(!self.can_pass());
         self.board.set_my_turn(active_player == self.player_index)
     }
 
-    fn on_anim(&mut self, t: f64) -> JsError {
-        self.board.on_anim(t)
+    // Under the strict opening rule, the first player has to play
+    fn can_pass(&self) -> bool {
+        self.active_player == self.player_index &&
+            !(self.rules.strict_opening && self.board.grid.is_empty())
     }
 
-    fn on_pan_start(&mut self, evt: PointerEvent) -> JsError {
-        self.board.on_pan_start(evt)
-    }
-
-    fn on_pan_move(&mut self, evt: PointerEvent) -> JsError {
-        self.board.on_pan_move(evt)
-    }
-
-    fn on_pan_end(&mut self, evt: Event) -> JsError {
-        self.board.on_pan_end(evt)
+    fn on_pass_button(&mut self) -> JsError {
+        if self.active_player != self.player_index ||
+           self.board.state != BoardState::Idle
+        {
+            return Ok(());
+        }
+        // Put back any tiles that we were in the middle of placing
+        self.board.on_reject_button(Event::new("Pass")?)?;
+        self.board.set_my_turn(false)?;
+        self.pass_button.set_disabled(true);
+        self.base.send(ClientMessage::Pass)
     }
 
     fn on_pointer_down(&mut self, evt: PointerEvent) -> JsError {
@@ -1627,7 +2403,72 @@ impl Playing {
         self.board.on_move_accepted(dealt)
     }
 
-    fn on_move_rejected(&mut self) -> JsError {
+    // The server rejects moves that are illegal or that arrive after our
+    // turn has timed out, so we put the tiles back into our hand.  An
+    // illegal move doesn't use up the turn, so we get to try again.
+    fn on_move_rejected(&mut self, err: MoveError) -> JsError {
+        self.on_information(&err.to_string())?;
+        self.board.on_reject_button(Event::new("MoveRejected")?)?;
+        let retry = !matches!(err, MoveError::OutOfTurn | MoveError::OutOfTime);
+        if retry && self.active_player == self.player_index {
+            self.board.set_my_turn(true)?;
+            self.pass_button.set_disabled(!self.can_pass());
+        }
+        Ok(())
+    }
+
+    fn on_turn_time(&mut self, ms: u64) -> JsError {
+        self.turn_deadline = Some(get_time_ms() + ms as f64);
+        if self.clock_interval.is_none() {
+            self.clock_interval = Some(web_sys::window()
+                .expect("no global `window` exists")
+                .set_interval_with_callback_and_timeout_and_arguments_0(
+                    self.clock_cb.as_ref().unchecked_ref(), CLOCK_STEP_MS)?);
+        }
+        self.draw_clocks()
+    }
+
+    fn on_clock_time(&mut self, index: usize, ms: u64) -> JsError {
+        if let Some(c) = self.clock_ms.get_mut(index) {
+            *c = ms;
+        }
+        self.draw_clocks()
+    }
+
+    fn on_clock_tick(&mut self) -> JsError {
+        self.draw_clocks()
+    }
+
+    fn stop_clock(&mut self) {
+        if let Some(handle) = self.clock_interval.take() {
+            web_sys::window()
+                .expect("no global `window` exists")
+                .clear_interval_with_handle(handle);
+        }
+    }
+
+    // Fills in the clock column of the score table.  The active player's
+    // turn counts down, while everyone else shows their total clock.
+    fn draw_clocks(&self) -> JsError {
+        if !self.rules.is_timed() {
+            return Ok(());
+        }
+        let rows = self.score_table.child_nodes();
+        for i in 0..self.player_names.len() {
+            let text = match self.turn_deadline {
+                Some(t) if i == self.active_player =>
+                    format_clock(t - get_time_ms()),
+                _ if self.rules.clock_secs > 0 =>
+                    format_clock(self.clock_ms[i] as f64),
+                _ => String::new(),
+            };
+            rows.item((i + 3) as u32)
+                .expect("Could not get table row")
+                .child_nodes()
+                .item(3)
+                .expect("Could not get clock cell")
+                .set_text_content(Some(&text));
+        }
         Ok(())
     }
 
@@ -1645,8 +2486,12 @@ impl Playing {
             if delta == 1 { "" } else { "s" }))
     }
 
-    fn on_finished(&mut self, winner: usize) -> JsError {
+    fn on_finished(&mut self, standings: &[Standing]) -> JsError {
         self.board.set_my_turn(false)?;
+        self.pass_button.set_disabled(true);
+        self.turn_deadline = None;
+        self.stop_clock();
+        self.draw_clocks()?;
 
         let children = self.score_table.child_nodes();
         children
@@ -1656,11 +2501,53 @@ impl Playing {
             .class_list()
             .remove_1("active")?;
 
-        if winner == self.player_index {
-            self.on_information("You win!")
-        } else {
-            self.on_information(&format!("{} wins!",
-                self.player_names[winner]))
+        // Fill in the results table, marking shared placements
+        self.results_rows.set_inner_html("");
+        for s in standings {
+            let tied = standings.iter().filter(|t| t.place == s.place).count();
+            let name = if s.player == self.player_index {
+                format!("{} (you)", self.player_names[s.player])
+            } else {
+                self.player_names[s.player].clone()
+            };
+            let b = &s.breakdown;
+            let tr = self.base.doc.create_element("tr")?;
+            if s.place == 1 {
+                tr.set_class_name("winner");
+            }
+            for text in &[
+                if tied > 1 { format!("={}", s.place) }
+                    else { s.place.to_string() },
+                name,
+                s.score.to_string(),
+                b.lines.to_string(),
+                format!("{} (+{})", b.qwirkles, b.qwirkle_bonus),
+                b.end_bonus.to_string(),
+                s.tiles_left.to_string(),
+            ] {
+                let td = self.base.doc.create_element("td")?;
+                td.set_text_content(Some(text));
+                tr.append_child(&td)?;
+            }
+            self.results_rows.append_child(&tr)?;
+        }
+        self.results_div.set_hidden(false);
+
+        let winners = standings.iter()
+            .filter(|s| s.place == 1)
+            .map(|s| if s.player == self.player_index {
+                    "you".to_string()
+                } else {
+                    self.player_names[s.player].clone()
+                })
+            .collect::<Vec<_>>();
+        match winners.len() {
+            0 => Ok(()),
+            1 if standings[0].player == self.player_index =>
+                self.on_information("You win!"),
+            1 => self.on_information(&format!("{} wins!", winners[0])),
+            n => self.on_information(&format!("It's a tie between {} and {}!",
+                winners[..n - 1].join(", "), winners[n - 1])),
         }
     }
 
@@ -1668,6 +2555,568 @@ impl Playing {
         self.board.pieces_remaining = remaining;
         self.board.update_exchange_div(self.active_player == self.player_index)
     }
+
+    // Shows a message from someone else's point of view, for replays and
+    // spectators.  Joining the room is handled by the caller.
+    fn apply(&mut self, msg: &ServerMessage, animate: bool) -> JsError {
+        use ServerMessage::*;
+        match msg {
+            Chat{from, message} => self.on_chat(from, message),
+            Information(message) => self.on_information(message),
+            NewPlayer(name) => self.on_new_player(name),
+            PlayerDisconnected(index) => self.on_player_disconnected(*index),
+            PlayerReconnected(index) => self.on_player_reconnected(*index),
+            PlayerTurn(active_player) => self.on_player_turn(*active_player),
+            NewHost(index) => self.on_new_host(*index),
+            PlayerMuted(index, muted) => self.on_player_muted(*index, *muted),
+            PlayerKicked(index) => self.on_player_kicked(*index),
+            ClockTime(index, ms) => self.on_clock_time(*index, *ms),
+            PiecesRemaining(remaining) => self.on_pieces_remaining(*remaining),
+            Played(pieces) if animate => self.on_played(pieces),
+            Played(pieces) => {
+                for (piece, x, y) in pieces {
+                    self.board.add_piece(*piece, *x, *y)?;
+                }
+                Ok(())
+            },
+            Swapped(count) => self.on_swapped(*count),
+            PlayerScore{delta, total} => self.on_player_score(*delta, *total),
+            ItsOver(standings) => self.on_finished(standings),
+            RulesChanged(rules) => self.on_rules_changed(*rules),
+
+            // There's no hand to show, turns don't count down in replays,
+            // and the lobby is skipped
+            JoinedRoom{..} | Spectating{..} | JoinFailed(_) |
+            MoveAccepted(_) | MoveRejected(_) | TurnTime(_) |
+            PlayerReady(_, _) | TurnOrder(_) | GameStarted(_) => Ok(()),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Spectating {
+    // Spectators see the same messages as a replay, except that turns
+    // count down as they happen
+    fn on_message(&mut self, msg: &ServerMessage) -> JsError {
+        match msg {
+            ServerMessage::TurnTime(ms) => self.playing.on_turn_time(*ms),
+            m => self.playing.apply(m, true),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Lobby {
+    fn new(playing: Playing) -> JsResult<Lobby> {
+        let doc = &playing.base.doc;
+        doc.get_element_by_id("playing")
+            .expect("Could not get playing div")
+            .class_list().add_1("lobby")?;
+        let lobby_div = doc.get_element_by_id("lobby_div")
+            .expect("Could not get lobby_div")
+            .dyn_into::<HtmlElement>()?;
+        lobby_div.set_hidden(false);
+        let host_div = doc.get_element_by_id("lobby_host_div")
+            .expect("Could not get lobby_host_div")
+            .dyn_into()?;
+        let order_list = doc.get_element_by_id("order_list")
+            .expect("Could not get order_list")
+            .dyn_into()?;
+
+        let ready_checkbox = doc.get_element_by_id("ready_checkbox")
+            .expect("Could not get ready_checkbox")
+            .dyn_into()?;
+        let ready_cb = set_event_cb(&ready_checkbox, "change",
+            move |_: Event| {
+                HANDLE.lock().unwrap().on_ready_changed()
+            });
+
+        let bot_select = doc.get_element_by_id("bot_select")
+            .expect("Could not get bot_select")
+            .dyn_into()?;
+        let add_bot_button = doc.get_element_by_id("add_bot_button")
+            .expect("Could not get add_bot_button");
+        let add_bot_cb = set_event_cb(&add_bot_button, "click",
+            move |_: Event| {
+                HANDLE.lock().unwrap().on_add_bot()
+            });
+
+        let start_button = doc.get_element_by_id("start_button")
+            .expect("Could not get start_button");
+        let start_cb = set_event_cb(&start_button, "click", move |_: Event| {
+            HANDLE.lock().unwrap().on_start_button()
+        });
+
+        // The limits on house rules come from pont-common, so that the
+        // browser's form validation matches what the server will accept
+        let rule_input = |id: &str, min: usize, max: usize| {
+            let e = doc.get_element_by_id(id)
+                .unwrap_or_else(|| panic!("Could not find {}", id))
+                .dyn_into::<HtmlInputElement>()?;
+            e.set_min(&min.to_string());
+            e.set_max(&max.to_string());
+            e.set_required(true);
+            Ok::<_, JsValue>(e)
+        };
+        let kinds_input = rule_input("kinds_input",
+            RuleSet::MIN_KINDS, RuleSet::MAX_KINDS)?;
+        let kinds_cb = set_event_cb(&kinds_input, "input", move |_: Event| {
+            HANDLE.lock().unwrap().on_kinds_changed()
+        });
+        let hand_size_input = rule_input("hand_size_input",
+                                         1, RuleSet::MAX_HAND_SIZE)?;
+        let copies_input = rule_input("copies_input",
+                                      1, RuleSet::MAX_COPIES)?;
+        let qwirkle_bonus_input = rule_input("qwirkle_bonus_input",
+            0, RuleSet::MAX_BONUS as usize)?;
+        let end_bonus_input = rule_input("end_bonus_input",
+            0, RuleSet::MAX_BONUS as usize)?;
+        let turn_secs_input = rule_input("turn_secs_input",
+            0, RuleSet::MAX_TURN_SECS as usize)?;
+        let clock_secs_input = rule_input("clock_secs_input",
+            0, RuleSet::MAX_CLOCK_SECS as usize)?;
+        let increment_secs_input = rule_input("increment_secs_input",
+            0, RuleSet::MAX_INCREMENT_SECS as usize)?;
+        let strict_opening_checkbox = doc
+            .get_element_by_id("strict_opening_checkbox")
+            .expect("Could not get strict_opening_checkbox")
+            .dyn_into()?;
+        let max_seats_input = rule_input("max_seats_input",
+                                         1, RuleSet::MAX_SEATS)?;
+        let lock_checkbox = doc.get_element_by_id("lock_checkbox")
+            .expect("Could not get lock_checkbox")
+            .dyn_into()?;
+
+        // Changes to any rule are sent to the server all at once
+        let rules_div = doc.get_element_by_id("rules_div")
+            .expect("Could not get rules_div");
+        let rules_cb = set_event_cb(&rules_div, "change", move |_: Event| {
+            HANDLE.lock().unwrap().on_rules_input()
+        });
+
+        let n = playing.player_names.len();
+        let mut out = Lobby {
+            playing,
+            lobby_div,
+            host_div,
+            order_list,
+            ready_checkbox,
+            bot_select,
+            kinds_input,
+            hand_size_input,
+            copies_input,
+            qwirkle_bonus_input,
+            end_bonus_input,
+            turn_secs_input,
+            clock_secs_input,
+            increment_secs_input,
+            strict_opening_checkbox,
+            max_seats_input,
+            lock_checkbox,
+
+            host: 0,
+            ready: vec![false; n],
+            order: (0..n).collect(),
+
+            _ready_cb: ready_cb,
+            _add_bot_cb: add_bot_cb,
+            _start_cb: start_cb,
+            _kinds_cb: kinds_cb,
+            _rules_cb: rules_cb,
+            _order_cbs: Vec::new(),
+        };
+        out.set_rules(out.playing.rules);
+        out.draw_order()?;
+        Ok(out)
+    }
+
+    fn is_host(&self) -> bool {
+        self.host == self.playing.player_index
+    }
+
+    // Reads the house rules from the lobby, falling back to the
+    // standard rules for anything that doesn't parse
+    fn rules(&self) -> RuleSet {
+        let d = RuleSet::default();
+        RuleSet {
+            kinds: self.kinds_input.value().parse()
+                .unwrap_or(d.kinds),
+            hand_size: self.hand_size_input.value().parse()
+                .unwrap_or(d.hand_size),
+            copies: self.copies_input.value().parse()
+                .unwrap_or(d.copies),
+            qwirkle_bonus: self.qwirkle_bonus_input.value().parse()
+                .unwrap_or(d.qwirkle_bonus),
+            end_bonus: self.end_bonus_input.value().parse()
+                .unwrap_or(d.end_bonus),
+            turn_secs: self.turn_secs_input.value().parse()
+                .unwrap_or(d.turn_secs),
+            clock_secs: self.clock_secs_input.value().parse()
+                .unwrap_or(d.clock_secs),
+            increment_secs: self.increment_secs_input.value().parse()
+                .unwrap_or(d.increment_secs),
+            strict_opening: self.strict_opening_checkbox.checked(),
+            max_seats: self.max_seats_input.value().parse()
+                .unwrap_or(d.max_seats),
+            lock_on_start: self.lock_checkbox.checked(),
+        }
+    }
+
+    fn set_rules(&self, rules: RuleSet) {
+        self.kinds_input.set_value(&rules.kinds.to_string());
+        self.hand_size_input.set_value(&rules.hand_size.to_string());
+        self.copies_input.set_value(&rules.copies.to_string());
+        self.qwirkle_bonus_input.set_value(&rules.qwirkle_bonus.to_string());
+        self.end_bonus_input.set_value(&rules.end_bonus.to_string());
+        self.turn_secs_input.set_value(&rules.turn_secs.to_string());
+        self.clock_secs_input.set_value(&rules.clock_secs.to_string());
+        self.increment_secs_input.set_value(
+            &rules.increment_secs.to_string());
+        self.strict_opening_checkbox.set_checked(rules.strict_opening);
+        self.max_seats_input.set_value(&rules.max_seats.to_string());
+        self.lock_checkbox.set_checked(rules.lock_on_start);
+    }
+
+    fn rule_inputs(&self) -> [&HtmlInputElement; 11] {
+        [&self.kinds_input, &self.hand_size_input, &self.copies_input,
+         &self.qwirkle_bonus_input, &self.end_bonus_input,
+         &self.turn_secs_input, &self.clock_secs_input,
+         &self.increment_secs_input, &self.strict_opening_checkbox,
+         &self.max_seats_input, &self.lock_checkbox]
+    }
+
+    // Lists players in turn order, with a checkmark for everyone who is
+    // ready.  The host gets buttons to move players up the list.
+    fn draw_order(&mut self) -> JsError {
+        self.order_list.set_inner_html("");
+        self._order_cbs.clear();
+        let is_host = self.is_host();
+        for (pos, i) in self.order.iter().enumerate() {
+            let doc = &self.playing.base.doc;
+            let li = doc.create_element("li")?;
+            li.set_text_content(Some(&self.playing.player_names[*i]));
+            if self.playing.kicked[*i] {
+                li.set_class_name("kicked");
+            }
+            if self.ready[*i] {
+                let c = doc.create_element("i")?;
+                c.set_class_name("fas fa-check");
+                li.append_child(&c)?;
+            }
+            if is_host && pos > 0 {
+                let b = doc.create_element("button")?;
+                b.set_inner_html("<i class=\"fas fa-arrow-up\"></i>");
+                self._order_cbs.push(set_event_cb(&b, "click",
+                    move |_: Event| {
+                        HANDLE.lock().unwrap().on_move_up(pos)
+                    }));
+                li.append_child(&b)?;
+            }
+            self.order_list.append_child(&li)?;
+        }
+        Ok(())
+    }
+
+    fn on_new_player(&mut self, name: &str) -> JsError {
+        self.playing.on_new_player(name)?;
+        self.order.push(self.ready.len());
+        self.ready.push(false);
+        self.draw_order()
+    }
+
+    fn on_new_host(&mut self, index: usize) -> JsError {
+        self.playing.on_new_host(index)?;
+        self.host = index;
+        self.host_div.set_hidden(!self.is_host());
+        for i in self.rule_inputs().iter() {
+            i.set_disabled(!self.is_host());
+        }
+        self.draw_order()
+    }
+
+    fn on_player_kicked(&mut self, index: usize) -> JsError {
+        self.playing.on_player_kicked(index)?;
+        self.draw_order()
+    }
+
+    fn on_ready_changed(&self) -> JsError {
+        self.playing.base.send(ClientMessage::Ready(
+            self.ready_checkbox.checked()))
+    }
+
+    fn on_player_ready(&mut self, index: usize, ready: bool) -> JsError {
+        self.ready[index] = ready;
+        if index == self.playing.player_index {
+            self.ready_checkbox.set_checked(ready);
+        }
+        self.draw_order()
+    }
+
+    fn on_add_bot(&self) -> JsError {
+        let difficulty = match self.bot_select.value().as_str() {
+            "easy" => Difficulty::Easy,
+            "hard" => Difficulty::Hard,
+            _ => Difficulty::Medium,
+        };
+        self.playing.base.send(ClientMessage::AddBot(difficulty))
+    }
+
+    // The Qwirkle bonus follows the size of the tile set, but can still be
+    // edited by hand afterwards
+    fn on_kinds_changed(&self) -> JsError {
+        if let Ok(kinds) = self.kinds_input.value().parse() {
+            let rules = RuleSet::for_kinds(kinds);
+            self.qwirkle_bonus_input.set_value(
+                &rules.qwirkle_bonus.to_string());
+        }
+        Ok(())
+    }
+
+    // Only sends rules that the server will accept, using the limits that
+    // were set on each input
+    fn on_rules_input(&self) -> JsError {
+        if self.rule_inputs().iter().all(|i| i.check_validity()) {
+            self.playing.base.send(ClientMessage::SetRules(self.rules()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_rules_changed(&mut self, rules: RuleSet) -> JsError {
+        self.set_rules(rules);
+        self.playing.on_rules_changed(rules)
+    }
+
+    fn on_move_up(&self, pos: usize) -> JsError {
+        let mut order = self.order.clone();
+        order.swap(pos - 1, pos);
+        self.playing.base.send(ClientMessage::SetTurnOrder(order))
+    }
+
+    fn on_turn_order(&mut self, order: &[usize]) -> JsError {
+        self.order = order.to_vec();
+        self.draw_order()
+    }
+
+    fn on_start_button(&self) -> JsError {
+        self.playing.base.send(ClientMessage::StartGame)
+    }
+
+    fn on_game_started(mut self, pieces: &[Piece]) -> JsResult<Playing> {
+        self.lobby_div.set_hidden(true);
+        self.playing.base.doc.get_element_by_id("playing")
+            .expect("Could not get playing div")
+            .class_list().remove_1("lobby")?;
+        for p in pieces.iter() {
+            self.playing.board.add_hand(*p)?;
+        }
+        self.playing.on_information("The game has started!")?;
+        self.playing.show_rules()?;
+        Ok(self.playing)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Watching {
+    fn new(base: Base, recording: Recording) -> JsResult<Watching> {
+        // Skip anything that happened before we joined the room
+        let messages = recording.messages.into_iter()
+            .map(|(_t, m)| m)
+            .skip_while(|m| !matches!(m, ServerMessage::JoinedRoom { .. }))
+            .collect::<Vec<_>>();
+
+        let playing = match &messages[0] {
+            ServerMessage::JoinedRoom{room_name, players, rules,
+                                      player_index, ..} =>
+                Playing::new(base, room_name, players, *rules,
+                             *player_index, &[], &[])?,
+            _ => return Err(JsValue::from_str("Replay has no game")),
+        };
+
+        let mut frames = vec![1];
+        for (i, m) in messages.iter().enumerate().skip(1) {
+            if let ServerMessage::PlayerTurn(_) = m {
+                frames.push(i + 1);
+            }
+        }
+        if frames[frames.len() - 1] != messages.len() {
+            frames.push(messages.len());
+        }
+
+        let doc = &playing.base.doc;
+        let scrub = doc.get_element_by_id("replay_scrub")
+            .expect("Could not get replay_scrub")
+            .dyn_into::<HtmlInputElement>()?;
+        scrub.set_max(&(frames.len() - 1).to_string());
+        let scrub_cb = set_event_cb(&scrub, "input", move |_: Event| {
+            HANDLE.lock().unwrap().on_replay_scrub()
+        });
+
+        let play_button = doc.get_element_by_id("replay_play")
+            .expect("Could not get replay_play")
+            .dyn_into::<HtmlButtonElement>()?;
+        let play_cb = set_event_cb(&play_button, "click", move |_: Event| {
+            HANDLE.lock().unwrap().on_replay_play()
+        });
+
+        let back_button = doc.get_element_by_id("replay_back")
+            .expect("Could not get replay_back");
+        let back_cb = set_event_cb(&back_button, "click", move |_: Event| {
+            HANDLE.lock().unwrap().on_replay_back()
+        });
+
+        let forward_button = doc.get_element_by_id("replay_forward")
+            .expect("Could not get replay_forward");
+        let forward_cb = set_event_cb(&forward_button, "click",
+            move |_: Event| {
+                HANDLE.lock().unwrap().on_replay_forward()
+            });
+
+        let exit_button = doc.get_element_by_id("replay_exit")
+            .expect("Could not get replay_exit");
+        let exit_cb = set_event_cb(&exit_button, "click", move |_: Event| {
+            HANDLE.lock().unwrap().on_replay_exit()
+        });
+
+        let turn_span = doc.get_element_by_id("replay_turn")
+            .expect("Could not get replay_turn")
+            .dyn_into()?;
+
+        let tick_cb = build_cb(move |_: JsValue| {
+            HANDLE.lock().unwrap().on_replay_tick()
+        });
+
+        let mut out = Watching {
+            playing,
+            messages,
+            frames,
+            frame: 0,
+
+            scrub,
+            play_button,
+            turn_span,
+            interval: None,
+
+            tick_cb,
+            _back_cb: back_cb,
+            _play_cb: play_cb,
+            _forward_cb: forward_cb,
+            _scrub_cb: scrub_cb,
+            _exit_cb: exit_cb,
+        };
+        out.seek(0)?;
+        Ok(out)
+    }
+
+    // Applies a single recorded message to the UI
+    fn apply(&mut self, msg: &ServerMessage, animate: bool) -> JsError {
+        let p = &mut self.playing;
+        match msg {
+            ServerMessage::JoinedRoom{players, active_player, board, ..} => {
+                p.reset()?;
+                p.load(players, board, &[])?;
+                p.on_player_turn(*active_player)
+            },
+            m => p.apply(m, animate),
+        }
+    }
+
+    // Shows the game as of the given frame.  Stepping forward by one frame
+    // is animated; anything else rebuilds the board from scratch.
+    fn seek(&mut self, frame: usize) -> JsError {
+        let frame = frame.min(self.frames.len() - 1);
+        self.playing.board.finish_animation()?;
+
+        let animate = frame == self.frame + 1;
+        let start = if frame > self.frame {
+            self.frames[self.frame]
+        } else {
+            0
+        };
+        for i in start..self.frames[frame] {
+            let msg = self.messages[i].clone();
+            self.apply(&msg, animate)?;
+        }
+
+        self.frame = frame;
+        self.scrub.set_value(&frame.to_string());
+        self.turn_span.set_text_content(Some(
+            &format!("{}/{}", frame, self.frames.len() - 1)));
+        Ok(())
+    }
+
+    fn pause(&mut self) -> JsError {
+        if let Some(handle) = self.interval.take() {
+            web_sys::window()
+                .expect("no global `window` exists")
+                .clear_interval_with_handle(handle);
+        }
+        self.play_button.set_inner_html("<i class=\"fas fa-play\"></i>");
+        Ok(())
+    }
+
+    fn on_replay_play(&mut self) -> JsError {
+        if self.interval.is_some() {
+            return self.pause();
+        }
+        // Start from the beginning if we're already at the end
+        if self.frame + 1 == self.frames.len() {
+            self.seek(0)?;
+        }
+        self.interval = Some(web_sys::window()
+            .expect("no global `window` exists")
+            .set_interval_with_callback_and_timeout_and_arguments_0(
+                self.tick_cb.as_ref().unchecked_ref(), REPLAY_STEP_MS)?);
+        self.play_button.set_inner_html("<i class=\"fas fa-pause\"></i>");
+        Ok(())
+    }
+
+    fn on_replay_tick(&mut self) -> JsError {
+        if self.frame + 1 < self.frames.len() {
+            self.seek(self.frame + 1)
+        } else {
+            self.pause()
+        }
+    }
+
+    fn on_replay_back(&mut self) -> JsError {
+        self.pause()?;
+        self.seek(self.frame.saturating_sub(1))
+    }
+
+    fn on_replay_forward(&mut self) -> JsError {
+        self.pause()?;
+        self.seek(self.frame + 1)
+    }
+
+    fn on_replay_scrub(&mut self) -> JsError {
+        self.pause()?;
+        self.seek(self.scrub.value().parse().unwrap_or(0))
+    }
+
+    fn on_replay_exit(&mut self) -> JsError {
+        self.pause()?;
+        self.playing.base.doc.location()
+            .expect("Could not get doc location")
+            .reload()
+    }
+}
+
+// Parses a saved replay, then switches into the replay viewer
+fn load_replay(text: &str) -> JsError {
+    let mut state = HANDLE.lock().unwrap();
+    match Recording::from_json(text) {
+        Ok(r) => if r.messages.iter().any(|(_t, m)|
+                        matches!(m, ServerMessage::JoinedRoom { .. })) {
+            state.on_watch_replay(r)
+        } else {
+            state.on_join_failed("Replay does not contain a game")
+        },
+        Err(e) => state.on_join_failed(&e.to_string()),
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -1676,29 +3125,79 @@ impl Playing {
 fn on_message(msg: ServerMessage) -> JsError {
     use ServerMessage::*;
     console_log!("Got message {:?}", msg);
-
     let mut state = HANDLE.lock().unwrap();
+    {   // Record the message, minus our secret rejoin token
+        let now = js_sys::Date::now() as u64;
+        let mut recording = RECORDING.lock().unwrap();
+
+        // The server only sends Played to the other players, so we record
+        // our own accepted play here; otherwise it would be missing from
+        // the board when the recording is played back.
+        if let (MoveAccepted(_), State::Playing(p)) = (&msg, &*state) {
+            let play = p.board.tentative_play();
+            if !play.is_empty() {
+                recording.push(now, Played(play));
+            }
+        }
+
+        let mut m = msg.clone();
+        if let JoinedRoom{token, ..} = &mut m {
+            token.clear();
+        }
+        recording.push(now, m);
+    }
+
+    if let State::Spectating(s) = &mut *state {
+        return s.on_message(&msg);
+    }
 
     match msg {
         JoinFailed(name) => state.on_join_failed(&name),
-        JoinedRoom{room_name, players, active_player, player_index, board, pieces} =>
-            state.on_joined_room(&room_name, &players,
-                                 active_player, player_index,
-                                 &board, &pieces),
-        Chat{from, message} => state.on_chat(&from, &message),
-        Information(message) => state.on_information(&message),
+        Spectating{room_name, players, active_player, board, rules,
+                   started} =>
+            state.on_spectating(&room_name, &players, rules,
+                                Some(active_player).filter(|_| started),
+                                &board),
+        JoinedRoom{room_name, players, active_player, player_index, board,
+                   pieces, rules, started, token} => {
+            save_token(&room_name, &token);
+            if started {
+                state.on_joined_room(&room_name, &players,
+                                     rules, player_index,
+                                     &board, &pieces)?;
+                state.on_player_turn(active_player)
+            } else {
+                state.on_joined_lobby(&room_name, &players,
+                                      rules, player_index)
+            }
+        },
+        NewHost(index) => state.on_new_host(index),
+        PlayerMuted(index, muted) =>
+            state.playing().on_player_muted(index, muted),
+        PlayerKicked(index) => state.on_player_kicked(index),
+        PlayerReady(index, ready) => state.on_player_ready(index, ready),
+        RulesChanged(rules) => state.on_rules_changed(rules),
+        TurnOrder(order) => state.on_turn_order(&order),
+        GameStarted(pieces) => state.on_game_started(&pieces),
+        Chat{from, message} => state.playing().on_chat(&from, &message),
+        Information(message) => state.playing().on_information(&message),
         NewPlayer(name) => state.on_new_player(&name),
-        PlayerDisconnected(index) => state.on_player_disconnected(index),
-        PlayerReconnected(index) => state.on_player_reconnected(index),
+        PlayerDisconnected(index) =>
+            state.playing().on_player_disconnected(index),
+        PlayerReconnected(index) =>
+            state.playing().on_player_reconnected(index),
         PlayerTurn(active_player) => state.on_player_turn(active_player),
-        PiecesRemaining(remaining) => state.on_pieces_remaining(remaining),
+        TurnTime(ms) => state.on_turn_time(ms),
+        ClockTime(index, ms) => state.playing().on_clock_time(index, ms),
+        PiecesRemaining(remaining) =>
+            state.playing().on_pieces_remaining(remaining),
         Played(pieces) => state.on_played(&pieces),
         Swapped(count) => state.on_swapped(count),
         MoveAccepted(dealt) => state.on_move_accepted(&dealt),
-        MoveRejected => state.on_move_rejected(),
+        MoveRejected(err) => state.on_move_rejected(err),
         PlayerScore{delta, total} =>
             state.on_player_score(delta, total),
-        ItsOver(winner) => state.on_finished(winner),
+        ItsOver(standings) => state.on_finished(&standings),
     }
 }
 
@@ -1761,6 +3260,10 @@ pub fn main() -> JsError {
         Ok(())
     }).forget();
     set_event_cb(&ws, "close", move |_: Event| -> JsError {
+        // Replays don't need the server, so keep watching
+        if let State::Watching(_) = *HANDLE.lock().unwrap() {
+            return Ok(());
+        }
         let doc = web_sys::window()
             .expect("no global `window` exists")
             .document()
@@ -0,0 +1,95 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pont_common::{Game, Piece, RuleSet};
+use pont_common::board::BoardGrid;
+
+type Play = Vec<(Piece, i32, i32)>;
+
+// Plays out a seeded game between four greedy players (who always make the
+// longest legal play), stopping once nobody can move or the bag is empty.
+// This gives a large, realistic late-game board, along with the plays that
+// were available on it at the end.
+fn late_game(kinds: usize, seed: u64) -> (BoardGrid, Vec<Play>) {
+    let rules = RuleSet::for_kinds(kinds);
+    let mut game = Game::with_rules(rules, seed);
+    let mut hands: Vec<Vec<Piece>> = (0..4)
+        .map(|_| game.deal(rules.hand_size)
+            .into_iter()
+            .flat_map(|(p, n)| std::iter::repeat_n(p, n))
+            .collect())
+        .collect();
+
+    let players = hands.len();
+    let mut stuck = 0;
+    for turn in 0.. {
+        let hand = &mut hands[turn % players];
+        let best = Game::legal_moves(&game.board, hand).into_iter()
+            .max_by_key(|play| play.len());
+        match best {
+            Some(play) => {
+                stuck = 0;
+                for (p, _, _) in play.iter() {
+                    let i = hand.iter().position(|q| q == p).unwrap();
+                    hand.swap_remove(i);
+                }
+                game.play(&play).unwrap();
+                let dealt = game.deal(play.len());
+                for (p, n) in dealt.into_iter() {
+                    hand.extend(std::iter::repeat_n(p, n));
+                }
+            },
+            None => stuck += 1,
+        }
+        if stuck == players || game.bag.is_empty() {
+            break;
+        }
+    }
+
+    let mut plays = Vec::new();
+    for hand in hands.iter() {
+        plays.extend(Game::legal_moves(&game.board, hand));
+    }
+    (game.board, plays)
+}
+
+// The old approach, which placed the play on a copy of the board and then
+// rescanned the whole thing
+fn rescan(board: &BoardGrid, play: &[(Piece, i32, i32)]) -> bool {
+    let mut b = board.clone();
+    for (p, x, y) in play {
+        b.insert((*x, *y), *p);
+    }
+    let cells = play.iter().map(|(_, x, y)| (*x, *y)).collect::<Vec<_>>();
+    Game::invalid(&b).is_empty() && Game::is_linear_connected(&b, &cells)
+}
+
+fn bench_validate(c: &mut Criterion) {
+    for kinds in [6, 7].iter() {
+        let (board, mut plays) = late_game(*kinds, 1);
+
+        // Also time some bad plays, made by shifting good ones off the board.
+        // (The tests check that validate and rescan agree.)
+        let bad = plays.iter()
+            .map(|play| play.iter()
+                .map(|(p, x, y)| (*p, x + 1000, *y))
+                .collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        plays.extend(bad);
+
+        let mut group = c.benchmark_group(
+            format!("{} kinds, {} pieces", kinds, board.len()));
+        group.bench_function("rescan", |b| b.iter(||
+            plays.iter().filter(|p| rescan(&board, black_box(p))).count()));
+        group.bench_function("validate", |b| b.iter(||
+            plays.iter()
+                .filter(|p| Game::validate(&board, black_box(p)).is_ok())
+                .count()));
+        group.bench_function("invalid_play", |b| b.iter(||
+            plays.iter()
+                .map(|p| Game::invalid_play(&board, black_box(p)).len())
+                .sum::<usize>()));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_validate);
+criterion_main!(benches);
@@ -0,0 +1,294 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::iter::FromIterator;
+use serde::{Serialize, Deserialize};
+
+use crate::Piece;
+
+const NEIGHBORS: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+// The pieces on the board, indexed by position.
+//
+// Alongside the cells, this keeps an index of which positions are filled in
+// each row and column (which also gives the bounding box), and the frontier
+// of empty cells that are next to a piece, i.e. the only places where a
+// play can go.  Every index is updated as pieces are inserted and removed,
+// so a search can place pieces on a board and take them back off without
+// rebuilding anything.
+//
+// It's serialized as a plain list of cells, since the indexes can all be
+// rebuilt from that.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(from = "Vec<((i32, i32), Piece)>", into = "Vec<((i32, i32), Piece)>")]
+pub struct BoardGrid {
+    cells: HashMap<(i32, i32), Piece>,
+    rows: BTreeMap<i32, BTreeSet<i32>>,
+    cols: BTreeMap<i32, BTreeSet<i32>>,
+    frontier: HashSet<(i32, i32)>,
+}
+
+impl BoardGrid {
+    pub fn new() -> BoardGrid {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn get(&self, pos: &(i32, i32)) -> Option<&Piece> {
+        self.cells.get(pos)
+    }
+
+    pub fn contains_key(&self, pos: &(i32, i32)) -> bool {
+        self.cells.contains_key(pos)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&(i32, i32), &Piece)> {
+        self.cells.iter()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=&(i32, i32)> {
+        self.cells.keys()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item=&Piece> {
+        self.cells.values()
+    }
+
+    // Places a piece, returning whatever was there before
+    pub fn insert(&mut self, pos: (i32, i32), piece: Piece) -> Option<Piece> {
+        let prev = self.cells.insert(pos, piece);
+        if prev.is_none() {
+            self.rows.entry(pos.1).or_default().insert(pos.0);
+            self.cols.entry(pos.0).or_default().insert(pos.1);
+            self.frontier.remove(&pos);
+            for n in Self::neighbors(pos) {
+                if !self.cells.contains_key(&n) {
+                    self.frontier.insert(n);
+                }
+            }
+        }
+        prev
+    }
+
+    // Takes a piece off the board, which undoes an insert
+    pub fn remove(&mut self, pos: &(i32, i32)) -> Option<Piece> {
+        let prev = self.cells.remove(pos)?;
+        for (index, line, at) in [(&mut self.rows, pos.1, pos.0),
+                                  (&mut self.cols, pos.0, pos.1)]
+        {
+            let set = index.get_mut(&line).unwrap();
+            set.remove(&at);
+            if set.is_empty() {
+                index.remove(&line);
+            }
+        }
+
+        // Neighbors stay on the frontier only if they're still next to
+        // another piece, and this cell joins it if it is
+        for n in Self::neighbors(*pos) {
+            if !self.cells.contains_key(&n) && !self.touches(n) {
+                self.frontier.remove(&n);
+            }
+        }
+        if self.touches(*pos) {
+            self.frontier.insert(*pos);
+        }
+        Some(prev)
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    // Returns the smallest and largest x and y positions of any piece, or
+    // None if the board is empty
+    pub fn bounds(&self) -> Option<((i32, i32), (i32, i32))> {
+        let (xmin, _) = self.cols.iter().next()?;
+        let (xmax, _) = self.cols.iter().next_back()?;
+        let (ymin, _) = self.rows.iter().next()?;
+        let (ymax, _) = self.rows.iter().next_back()?;
+        Some(((*xmin, *ymin), (*xmax, *ymax)))
+    }
+
+    // Returns the x positions of every piece in row y, in order
+    pub fn row(&self, y: i32) -> impl Iterator<Item=i32> + '_ {
+        self.rows.get(&y).into_iter().flatten().copied()
+    }
+
+    // Returns the y positions of every piece in column x, in order
+    pub fn column(&self, x: i32) -> impl Iterator<Item=i32> + '_ {
+        self.cols.get(&x).into_iter().flatten().copied()
+    }
+
+    // Returns every empty cell that is next to a piece
+    pub fn frontier(&self) -> impl Iterator<Item=&(i32, i32)> {
+        self.frontier.iter()
+    }
+
+    pub fn on_frontier(&self, pos: &(i32, i32)) -> bool {
+        self.frontier.contains(pos)
+    }
+
+    // Checks whether any of the neighbors of a cell are filled
+    pub fn touches(&self, pos: (i32, i32)) -> bool {
+        Self::neighbors(pos).any(|n| self.cells.contains_key(&n))
+    }
+
+    fn neighbors(pos: (i32, i32)) -> impl Iterator<Item=(i32, i32)> {
+        NEIGHBORS.iter().map(move |(dx, dy)| (pos.0 + dx, pos.1 + dy))
+    }
+}
+
+impl FromIterator<((i32, i32), Piece)> for BoardGrid {
+    fn from_iter<I>(iter: I) -> Self
+        where I: IntoIterator<Item=((i32, i32), Piece)>
+    {
+        let mut out = Self::new();
+        for (pos, piece) in iter {
+            out.insert(pos, piece);
+        }
+        out
+    }
+}
+
+impl From<Vec<((i32, i32), Piece)>> for BoardGrid {
+    fn from(cells: Vec<((i32, i32), Piece)>) -> Self {
+        cells.into_iter().collect()
+    }
+}
+
+impl From<BoardGrid> for Vec<((i32, i32), Piece)> {
+    fn from(board: BoardGrid) -> Self {
+        board.cells.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Color::*, Shape::*};
+
+    fn frontier(b: &BoardGrid) -> Vec<(i32, i32)> {
+        let mut out = b.frontier().cloned().collect::<Vec<_>>();
+        out.sort();
+        out
+    }
+
+    // Rebuilding from the cells gives the frontier from scratch, which is
+    // what incremental updates have to match
+    fn rebuilt(b: &BoardGrid) -> Vec<(i32, i32)> {
+        frontier(&b.iter().map(|(k, v)| (*k, *v)).collect())
+    }
+
+    #[test]
+    fn frontier_after_insert() {
+        let mut b = BoardGrid::new();
+        assert!(frontier(&b).is_empty());
+        b.insert((0, 0), (Cross, Orange));
+        assert_eq!(frontier(&b), vec![(-1, 0), (0, -1), (0, 1), (1, 0)]);
+        b.insert((1, 0), (Circle, Orange));
+        assert_eq!(frontier(&b), vec![(-1, 0), (0, -1), (0, 1),
+                                      (1, -1), (1, 1), (2, 0)]);
+        assert!(!b.on_frontier(&(1, 0)));
+    }
+
+    #[test]
+    fn frontier_after_remove() {
+        let mut b = BoardGrid::new();
+        b.insert((0, 0), (Cross, Orange));
+        b.insert((1, 0), (Circle, Orange));
+        b.insert((1, 1), (Circle, Red));
+
+        // Removing the end of a line: (1, 1) is next to (1, 0), so it joins
+        // the frontier, and its other neighbors leave
+        assert_eq!(b.remove(&(1, 1)), Some((Circle, Red)));
+        assert!(b.on_frontier(&(1, 1)));
+        assert!(!b.on_frontier(&(1, 2)));
+        assert!(!b.on_frontier(&(2, 1)));
+        assert!(b.on_frontier(&(0, 1)));
+        assert_eq!(frontier(&b), rebuilt(&b));
+
+        // Removing a piece in the middle: the cell stays on the frontier,
+        // and neighbors that still touch a piece stay too
+        b.insert((2, 0), (Square, Orange));
+        b.remove(&(1, 0));
+        assert!(b.on_frontier(&(1, 0)));
+        assert!(b.on_frontier(&(-1, 0)));
+        assert!(b.on_frontier(&(3, 0)));
+        assert!(!b.on_frontier(&(1, 1)));
+        assert_eq!(frontier(&b), rebuilt(&b));
+
+        // Removing the last piece empties the frontier
+        b.remove(&(0, 0));
+        b.remove(&(2, 0));
+        assert!(b.is_empty());
+        assert!(frontier(&b).is_empty());
+
+        // Removing an empty cell changes nothing
+        assert_eq!(b.remove(&(5, 5)), None);
+        assert!(frontier(&b).is_empty());
+    }
+
+    #[test]
+    fn indexes_after_insert_and_remove() {
+        let mut b = BoardGrid::new();
+        assert_eq!(b.bounds(), None);
+        b.insert((0, 0), (Cross, Orange));
+        b.insert((2, 0), (Circle, Orange));
+        b.insert((2, -3), (Circle, Red));
+        assert_eq!(b.bounds(), Some(((0, -3), (2, 0))));
+        assert_eq!(b.row(0).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(b.column(2).collect::<Vec<_>>(), vec![-3, 0]);
+        assert_eq!(b.row(1).count(), 0);
+
+        // Replacing a piece doesn't change the indexes
+        b.insert((2, 0), (Star, Blue));
+        assert_eq!(b.row(0).collect::<Vec<_>>(), vec![0, 2]);
+
+        // Emptying a row or column shrinks the bounds
+        b.remove(&(2, -3));
+        assert_eq!(b.bounds(), Some(((0, 0), (2, 0))));
+        assert_eq!(b.column(2).collect::<Vec<_>>(), vec![0]);
+        b.remove(&(0, 0));
+        assert_eq!(b.bounds(), Some(((2, 0), (2, 0))));
+        assert_eq!(b.row(0).collect::<Vec<_>>(), vec![2]);
+        b.remove(&(2, 0));
+        assert_eq!(b.bounds(), None);
+        assert_eq!(b, BoardGrid::new());
+    }
+
+    // Only the cells are serialized, so the indexes have to come back
+    // when the board is loaded
+    #[test]
+    fn indexes_rebuilt_on_deserialize() {
+        let b: BoardGrid = vec![((0, 0), (Cross, Orange)),
+                                ((0, 1), (Cross, Red)),
+                                ((-1, 1), (Star, Red))].into();
+        let json = serde_json::to_string(&b).unwrap();
+        let out: BoardGrid = serde_json::from_str(&json).unwrap();
+        assert_eq!(out, b);
+        assert_eq!(out.bounds(), Some(((-1, 0), (0, 1))));
+        assert_eq!(out.row(1).collect::<Vec<_>>(), vec![-1, 0]);
+        assert_eq!(frontier(&out), rebuilt(&b));
+    }
+
+    #[test]
+    fn insert_then_remove_is_undone() {
+        let mut b: BoardGrid = vec![((0, 0), (Cross, Orange)),
+                                    ((0, 1), (Cross, Red)),
+                                    ((1, 1), (Star, Red))].into();
+        let before = b.clone();
+        for pos in [(2, 1), (1, 0), (0, 2)].iter() {
+            b.insert(*pos, (Clover, Blue));
+        }
+        for pos in [(1, 0), (0, 2), (2, 1)].iter() {
+            b.remove(pos);
+        }
+        assert_eq!(b, before);
+    }
+}
@@ -1,20 +1,56 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use serde::{Serialize, Deserialize};
 
-use rand::thread_rng;
+use rand::{thread_rng, Rng, SeedableRng};
 use rand::seq::SliceRandom;
+use rand_pcg::Pcg32;
+
+use crate::board::BoardGrid;
+
+pub mod board;
+pub mod replay;
+pub mod strategy;
 
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum ClientMessage {
     CreateRoom(String),
     JoinRoom(String, String),
+    Rejoin(String, String),
+
+    // Watch a room without taking a seat
+    Spectate(String),
     Chat(String),
     Play(Vec<(Piece, i32, i32)>),
     Swap(Vec<Piece>),
+    Pass,
+    AddBot(Difficulty),
+    AllowNameRejoin(bool),
+
+    // Lobby controls, before the game starts.  Everyone can mark themselves
+    // as ready; only the host can change the rules, pick the turn order
+    // (as a list of player indices), and start the game.
+    Ready(bool),
+    SetRules(RuleSet),
+    SetTurnOrder(Vec<usize>),
+    StartGame,
+
+    // Moderation, which only the host can do at any time.  A kicked player
+    // loses their seat for good, and their hand goes back into the bag.
+    Kick(usize),
+    Mute(usize, bool),
+    TransferHost(usize),
 
     Disconnected,
 }
 
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum ServerMessage {
     JoinedRoom {
@@ -24,6 +60,23 @@ pub enum ServerMessage {
         player_index: usize,
         board: Vec<((i32, i32), Piece)>,
         pieces: Vec<Piece>,
+
+        rules: RuleSet,
+
+        // Whether hands have been dealt, or the room is still in the lobby
+        started: bool,
+
+        // Secret token which lets this player reclaim their seat later
+        token: String,
+    },
+    // Sent instead of JoinedRoom to spectators, who have no seat or hand
+    Spectating {
+        room_name: String,
+        players: Vec<(String, u32, bool)>,
+        active_player: usize,
+        board: Vec<((i32, i32), Piece)>,
+        rules: RuleSet,
+        started: bool,
     },
     JoinFailed(String),
     Chat {
@@ -35,19 +88,174 @@ pub enum ServerMessage {
     PlayerReconnected(usize),
     PlayerDisconnected(usize),
     PlayerTurn(usize),
+    NewHost(usize),
+    PlayerMuted(usize, bool),
+    PlayerKicked(usize),
+
+    // Lobby updates, sent until the host starts the game
+    PlayerReady(usize, bool),
+    RulesChanged(RuleSet),
+    TurnOrder(Vec<usize>),
+
+    // The game has started, and these pieces are your hand
+    GameStarted(Vec<Piece>),
+
+    // Milliseconds left before the active player's turn times out
+    TurnTime(u64),
+
+    // Milliseconds left on a player's total clock
+    ClockTime(usize, u64),
+
     Played(Vec<(Piece, i32, i32)>),
     Swapped(usize),
     MoveAccepted(Vec<Piece>),
-    MoveRejected,
+    MoveRejected(MoveError),
     PlayerScore {
         delta: u32,
         total: u32,
     },
     PiecesRemaining(usize),
-    ItsOver(usize),
+
+    // Final standings, sorted from first place to last
+    ItsOver(Vec<Standing>),
+}
+
+// Points earned over a play or a whole game, split up by where they came from
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ScoreBreakdown {
+    // Points for the lengths of lines that were made or extended
+    pub lines: u32,
+
+    // Number of lines that were completed, and the bonus points for them
+    pub qwirkles: u32,
+    pub qwirkle_bonus: u32,
+
+    // Bonus for ending the game by emptying a hand
+    pub end_bonus: u32,
+}
+
+impl ScoreBreakdown {
+    pub fn total(&self) -> u32 {
+        self.lines + self.qwirkle_bonus + self.end_bonus
+    }
+}
+
+// A line that a play would score, from one end to the other
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScoredLine {
+    pub cells: Vec<(i32, i32)>,
+    pub points: u32,
+    pub qwirkle: bool,
+}
+
+// What a play is worth, both in total and line by line
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PlayScore {
+    pub breakdown: ScoreBreakdown,
+    pub lines: Vec<ScoredLine>,
+}
+
+impl std::ops::AddAssign for ScoreBreakdown {
+    fn add_assign(&mut self, other: ScoreBreakdown) {
+        self.lines += other.lines;
+        self.qwirkles += other.qwirkles;
+        self.qwirkle_bonus += other.qwirkle_bonus;
+        self.end_bonus += other.end_bonus;
+    }
+}
+
+// Reasons that a play or swap can be turned down
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MoveError {
+    NoPieces,
+    Occupied,
+    NotLinear,
+    Gap,
+    Disconnected,
+    DuplicateTile,
+    MixedLine,
+    UnownedPiece,
+
+    // The number of pieces left in the bag
+    BagTooSmall(usize),
+
+    // The number of pieces that the opening play must use
+    ShortOpening(usize),
+    OpeningSwap,
+    OpeningPass,
+
+    OutOfTurn,
+    OutOfTime,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoveError::NoPieces => write!(f, "A play needs at least one piece"),
+            MoveError::Occupied => write!(f,
+                "Pieces can't be placed on top of other pieces"),
+            MoveError::NotLinear => write!(f,
+                "A play must be in a single row or column"),
+            MoveError::Gap => write!(f,
+                "The pieces in a play can't have gaps between them"),
+            MoveError::Disconnected => write!(f,
+                "A play must connect to the pieces on the board"),
+            MoveError::DuplicateTile => write!(f,
+                "A line can't contain the same piece twice"),
+            MoveError::MixedLine => write!(f,
+                "Every piece in a line must share a shape or a color"),
+            MoveError::UnownedPiece => write!(f,
+                "Those pieces aren't in your hand"),
+            MoveError::BagTooSmall(n) => write!(f,
+                "There are only {} pieces left in the bag", n),
+            MoveError::ShortOpening(n) => write!(f,
+                "The opening play must use {} pieces", n),
+            MoveError::OpeningSwap => write!(f,
+                "The opening play can't be a swap"),
+            MoveError::OpeningPass => write!(f,
+                "The opening player can't pass"),
+            MoveError::OutOfTurn => write!(f, "It isn't your turn"),
+            MoveError::OutOfTime => write!(f, "You ran out of time"),
+        }
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+// One player's result at the end of the game
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Standing {
+    pub player: usize,
+
+    // Placement, starting from 1.  Tied players share a placement, and the
+    // next placement is skipped (e.g. 1, 1, 3).
+    pub place: usize,
+
+    pub score: u32,
+    pub breakdown: ScoreBreakdown,
+
+    // Number of tiles left in the player's hand when the game ended
+    pub tiles_left: usize,
+}
+
+impl Standing {
+    // Sorts standings by score and assigns placements, with ties sharing
+    // the same placement
+    pub fn rank(standings: &mut [Standing]) {
+        standings.sort_by_key(|s| (std::cmp::Reverse(s.score), s.player));
+        for i in 0..standings.len() {
+            standings[i].place =
+                if i > 0 && standings[i].score == standings[i - 1].score {
+                    standings[i - 1].place
+                } else {
+                    i + 1
+                };
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq,
+         PartialOrd, Serialize)]
 pub enum Shape {
     Clover,
     Star,
@@ -55,9 +263,11 @@ pub enum Shape {
     Diamond,
     Cross,
     Circle,
+    Triangle,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq,
+         PartialOrd, Serialize)]
 pub enum Color {
     Orange,
     Yellow,
@@ -65,57 +275,372 @@ pub enum Color {
     Red,
     Blue,
     Purple,
+    Pink,
 }
 
 pub type Piece = (Shape, Color);
 
+impl Shape {
+    // Every shape, in the order that they're added to larger tile sets
+    pub const ALL: [Shape; 7] = [Shape::Clover, Shape::Star, Shape::Square,
+                                 Shape::Diamond, Shape::Cross, Shape::Circle,
+                                 Shape::Triangle];
+}
+
+impl Color {
+    pub const ALL: [Color; 7] = [Color::Orange, Color::Yellow, Color::Green,
+                                 Color::Red, Color::Blue, Color::Purple,
+                                 Color::Pink];
+}
+
+// House rules, which are picked by whoever creates the room
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RuleSet {
+    // Number of shapes and of colors in play.  A line with this many pieces
+    // is complete, and earns the Qwirkle bonus.
+    pub kinds: usize,
+
+    pub hand_size: usize,
+
+    // Number of copies of each distinct piece in the bag
+    pub copies: usize,
+
+    // Extra points for completing a line of every shape or color
+    pub qwirkle_bonus: u32,
+
+    // Extra points for the player who ends the game by emptying their hand
+    pub end_bonus: u32,
+
+    // Time limits in seconds, where zero means no limit.  Each turn is
+    // limited to turn_secs, and each player has a total clock of clock_secs,
+    // which gains increment_secs after every turn (like a chess clock).
+    pub turn_secs: u32,
+    pub clock_secs: u32,
+    pub increment_secs: u32,
+
+    // If set, the first play of the game must use as many pieces as the
+    // longest line in that player's hand
+    pub strict_opening: bool,
+
+    // Most players (including bots) that can sit in the room, and whether
+    // new players are turned away once the game has started
+    pub max_seats: usize,
+    pub lock_on_start: bool,
+}
+
+impl Default for RuleSet {
+    fn default() -> RuleSet {
+        RuleSet::for_kinds(6)
+    }
+}
+
+impl RuleSet {
+    pub const MIN_KINDS: usize = 3;
+    pub const MAX_KINDS: usize = 7;
+    pub const MAX_HAND_SIZE: usize = 8;
+    pub const MAX_COPIES: usize = 4;
+    pub const MAX_BONUS: u32 = 24;
+    pub const MAX_TURN_SECS: u32 = 600;
+    pub const MAX_CLOCK_SECS: u32 = 7200;
+    pub const MAX_INCREMENT_SECS: u32 = 60;
+    pub const MAX_SEATS: usize = 8;
+
+    // Returns the standard rules for a tile set with the given number of
+    // shapes and colors, where completing a line is worth double
+    pub fn for_kinds(kinds: usize) -> RuleSet {
+        RuleSet {
+            kinds,
+            hand_size: 6,
+            copies: 3,
+            qwirkle_bonus: kinds as u32,
+            end_bonus: 6,
+            turn_secs: 0,
+            clock_secs: 0,
+            increment_secs: 0,
+            strict_opening: false,
+            max_seats: 4,
+            lock_on_start: true,
+        }
+    }
+
+    // Checks that the rules are within the range that the client can show,
+    // and that there are enough pieces to deal every seat a full hand.
+    // If they aren't, then returns a message explaining why.
+    pub fn is_valid(&self) -> Result<(), String> {
+        if !(Self::MIN_KINDS..=Self::MAX_KINDS).contains(&self.kinds) {
+            Err(format!("There must be {} to {} shapes and colors",
+                        Self::MIN_KINDS, Self::MAX_KINDS))
+        } else if !(1..=Self::MAX_HAND_SIZE).contains(&self.hand_size) {
+            Err(format!("Hands must hold 1 to {} pieces",
+                        Self::MAX_HAND_SIZE))
+        } else if !(1..=Self::MAX_COPIES).contains(&self.copies) {
+            Err(format!("There must be 1 to {} copies of each piece",
+                        Self::MAX_COPIES))
+        } else if self.qwirkle_bonus > Self::MAX_BONUS ||
+                  self.end_bonus > Self::MAX_BONUS
+        {
+            Err(format!("Bonuses can be at most {} points", Self::MAX_BONUS))
+        } else if self.turn_secs > Self::MAX_TURN_SECS {
+            Err(format!("Turns can be at most {} seconds",
+                        Self::MAX_TURN_SECS))
+        } else if self.clock_secs > Self::MAX_CLOCK_SECS {
+            Err(format!("Clocks can be at most {} seconds",
+                        Self::MAX_CLOCK_SECS))
+        } else if self.increment_secs > Self::MAX_INCREMENT_SECS {
+            Err(format!("Clock increments can be at most {} seconds",
+                        Self::MAX_INCREMENT_SECS))
+        } else if !(1..=Self::MAX_SEATS).contains(&self.max_seats) {
+            Err(format!("There must be 1 to {} seats", Self::MAX_SEATS))
+        } else if self.max_seats * self.hand_size >
+                  self.kinds * self.kinds * self.copies
+        {
+            Err("There aren't enough pieces to deal every seat".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Returns true if turns are limited by either clock
+    pub fn is_timed(&self) -> bool {
+        self.turn_secs > 0 || self.clock_secs > 0
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Game {
-    pub board: HashMap<(i32, i32), Piece>,
+    pub board: BoardGrid,
     pub bag: Vec<Piece>,
+    pub rules: RuleSet,
+
+    // All shuffling is driven by this RNG, so that a game can be reproduced
+    // exactly from its seed (as long as the same moves are made).
+    pub seed: u64,
+    rng: Pcg32,
 }
 
 impl Game {
-    pub fn play(&mut self, ps: &[(Piece, i32, i32)]) -> Option<u32> {
-        for (p, x, y) in ps {
-            use std::collections::hash_map::Entry;
-            match self.board.entry((*x, *y)) {
-                Entry::Occupied(_) => return None,
-                Entry::Vacant(v) => { v.insert(*p); }
+    // Builds a new game with the standard rules, with the bag shuffled by
+    // an RNG with the given seed
+    pub fn new(seed: u64) -> Game {
+        Self::with_rules(RuleSet::default(), seed)
+    }
+
+    pub fn with_rules(rules: RuleSet, seed: u64) -> Game {
+        let mut rng = Pcg32::seed_from_u64(seed);
+        let mut bag = Self::all_pieces(&rules);
+        bag.shuffle(&mut rng);
+
+        Game {
+            board: BoardGrid::new(), bag, rules, seed, rng
+        }
+    }
+
+    // Returns every piece in the game, in a fixed order
+    pub fn all_pieces(rules: &RuleSet) -> Vec<Piece> {
+        let mut out = Vec::new();
+        for c in Color::ALL.iter().take(rules.kinds) {
+            for s in Shape::ALL.iter().take(rules.kinds) {
+                for _ in 0..rules.copies {
+                    out.push((*s, *c));
+                }
             }
         }
-        let mut score = 0;
-        let mut seen_rows = HashSet::new();
-        let mut seen_cols = HashSet::new();
-        let f = |v: Vec<(Piece, (i32, i32))>| -> Vec<(i32, i32)> {
-            let mut v = v.into_iter()
-                .map(|(_p, (x, y))| (x, y))
-                .collect::<Vec<_>>();
-            v.sort();
-            v
-        };
+        out
+    }
+
+    pub fn play(&mut self, ps: &[(Piece, i32, i32)])
+        -> Result<u32, MoveError>
+    {
+        self.play_scored(ps).map(|s| s.total())
+    }
+
+    // Plays the given pieces, returning a breakdown of the points earned.
+    // This only checks that the cells are empty (leaving the board alone
+    // if they aren't); use check_play first to make sure that the play is
+    // legal.
+    pub fn play_scored(&mut self, ps: &[(Piece, i32, i32)])
+        -> Result<ScoreBreakdown, MoveError>
+    {
+        let mut cells = HashSet::new();
+        for (_p, x, y) in ps {
+            if self.board.contains_key(&(*x, *y)) || !cells.insert((*x, *y)) {
+                return Err(MoveError::Occupied);
+            }
+        }
+        let score = Self::score_play(&self.board, self.rules, ps);
+        for (p, x, y) in ps {
+            self.board.insert((*x, *y), *p);
+        }
+        Ok(score.breakdown)
+    }
+
+    // Works out what a play would score, without changing the board.  Each
+    // row or column of two or more pieces that passes through a new piece
+    // scores a point per piece, plus a bonus if it's a complete line.  The
+    // play is assumed to be valid (see validate).
+    pub fn score_play(board: &BoardGrid, rules: RuleSet,
+                      ps: &[(Piece, i32, i32)]) -> PlayScore
+    {
+        let get = Self::overlay(board, ps);
+        let mut out = PlayScore::default();
+        let mut seen = HashSet::new();
         for (_piece, x, y) in ps {
-            let row = f(Self::explore_from(&self.board, |i| (*x + i, *y)));
-            if row.len() > 1 && seen_rows.insert(row[0]) {
-                score += row.len();
-                if row.len() == 6 {
-                    score += 6;
+            for dir in [(1, 0), (0, 1)].iter() {
+                // Walk back to the start of the line, then count its length
+                let mut start = (*x, *y);
+                while get((start.0 - dir.0, start.1 - dir.1)).is_some() {
+                    start = (start.0 - dir.0, start.1 - dir.1);
+                }
+                let len = Self::line(&get, start, *dir).count();
+                if len > 1 && seen.insert((start, *dir)) {
+                    let qwirkle = len == rules.kinds;
+                    let bonus = if qwirkle { rules.qwirkle_bonus } else { 0 };
+                    out.breakdown.lines += len as u32;
+                    if qwirkle {
+                        out.breakdown.qwirkles += 1;
+                        out.breakdown.qwirkle_bonus += bonus;
+                    }
+                    out.lines.push(ScoredLine {
+                        cells: (0..len as i32)
+                            .map(|i| (start.0 + i * dir.0, start.1 + i * dir.1))
+                            .collect(),
+                        points: len as u32 + bonus,
+                        qwirkle,
+                    });
                 }
             }
+        }
+        out
+    }
 
-            let col = f(Self::explore_from(&self.board, |i| (*x, *y + i)));
-            if col.len() > 1 && seen_cols.insert(col[0]) {
-                score += col.len();
-                if col.len() == 6 {
-                    score += 6;
-                }
+    // Checks whether the given pieces can be played on the current board,
+    // returning the first problem found.  The board itself isn't changed.
+    pub fn check_play(&self, ps: &[(Piece, i32, i32)])
+        -> Result<(), MoveError>
+    {
+        Self::validate(&self.board, ps)
+    }
+
+    // Checks a play against a board that is assumed to be valid.  Rather
+    // than rescanning the whole board, this only looks at the lines that
+    // pass through the new pieces, and at whether the play touches the
+    // existing board.
+    pub fn validate(board: &BoardGrid,
+                    ps: &[(Piece, i32, i32)]) -> Result<(), MoveError>
+    {
+        let get = Self::overlay(board, ps);
+        let dir = Self::check_placement(board, ps, &get)?;
+
+        // The line along the play is shared by every new piece, so it's
+        // only checked once; then each piece has its own crossing line.
+        let (_, x, y) = ps[0];
+        if let Some(e) = Self::line_error(Self::line(&get, (x, y), dir)) {
+            return Err(e);
+        }
+        let cross = (dir.1, dir.0);
+        for (_, x, y) in ps {
+            if let Some(e) = Self::line_error(Self::line(&get, (*x, *y), cross))
+            {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the positions of pieces in a play that would be in an invalid
+    // line, or every position if the play isn't placed correctly.  This is
+    // used to highlight bad pieces while a play is being put together.
+    pub fn invalid_play(board: &BoardGrid,
+                        ps: &[(Piece, i32, i32)]) -> HashSet<(i32, i32)>
+    {
+        let get = Self::overlay(board, ps);
+        if Self::check_placement(board, ps, &get).is_err() {
+            return ps.iter().map(|(_, x, y)| (*x, *y)).collect();
+        }
+        ps.iter()
+            .map(|(_, x, y)| (*x, *y))
+            .filter(|c| [(1, 0), (0, 1)].iter().any(|dir|
+                Self::line_error(Self::line(&get, *c, *dir)).is_some()))
+            .collect()
+    }
+
+    // Looks up a cell on the board, with the given play placed on top of it.
+    // Plays are short, so a linear search beats building a map.
+    fn overlay<'a>(board: &'a BoardGrid,
+                   ps: &'a [(Piece, i32, i32)])
+        -> impl Fn((i32, i32)) -> Option<Piece> + 'a
+    {
+        move |c| ps.iter()
+            .find(|(_, x, y)| (*x, *y) == c)
+            .map(|(p, _, _)| *p)
+            .or_else(|| board.get(&c).copied())
+    }
+
+    // Checks that a play is non-empty, doesn't overlap anything, is in a
+    // single row or column without gaps, and touches the existing board.
+    // Returns the direction of the play (which is +x for a single piece).
+    fn check_placement<F>(board: &BoardGrid,
+                          ps: &[(Piece, i32, i32)], get: &F)
+        -> Result<(i32, i32), MoveError>
+        where F: Fn((i32, i32)) -> Option<Piece>
+    {
+        if ps.is_empty() {
+            return Err(MoveError::NoPieces);
+        }
+        for (i, (_, x, y)) in ps.iter().enumerate() {
+            if board.contains_key(&(*x, *y)) ||
+               ps[..i].iter().any(|(_, a, b)| (a, b) == (x, y))
+            {
+                return Err(MoveError::Occupied);
+            }
+        }
+
+        let (_, x0, y0) = ps[0];
+        let dir = if ps.iter().all(|(_, _, y)| *y == y0) {
+            (1, 0)
+        } else if ps.iter().all(|(_, x, _)| *x == x0) {
+            (0, 1)
+        } else {
+            return Err(MoveError::NotLinear);
+        };
+
+        // Walk from one end of the play to the other, which must be filled
+        // in by either new pieces or pieces on the board
+        let along = |(_, x, y): &(Piece, i32, i32)| x * dir.0 + y * dir.1;
+        let lo = ps.iter().map(along).min().unwrap();
+        let hi = ps.iter().map(along).max().unwrap();
+        for t in lo..=hi {
+            let c = if dir.0 == 1 { (t, y0) } else { (x0, t) };
+            if get(c).is_none() {
+                return Err(MoveError::Gap);
             }
         }
-        Some(score as u32)
+
+        // Every play after the first has to touch the existing board
+        let touches = board.is_empty() ||
+            ps.iter().any(|(_, x, y)| board.on_frontier(&(*x, *y)));
+        if touches {
+            Ok(dir)
+        } else {
+            Err(MoveError::Disconnected)
+        }
+    }
+
+    // Returns the pieces in the line through c along the given axis,
+    // without allocating
+    fn line<'a, F>(get: &'a F, c: (i32, i32), dir: (i32, i32))
+        -> impl Iterator<Item=Piece> + 'a
+        where F: Fn((i32, i32)) -> Option<Piece>
+    {
+        let walk = move |sign: i32, start: i32| (start..)
+            .map(move |i| get((c.0 + sign * i * dir.0, c.1 + sign * i * dir.1)))
+            .take_while(|p| p.is_some())
+            .flatten();
+        walk(1, 0).chain(walk(-1, 1))
     }
 
     pub fn shuffle(&mut self) {
-        self.bag.shuffle(&mut thread_rng());
+        self.bag.shuffle(&mut self.rng);
     }
 
     pub fn deal(&mut self, n: usize) -> HashMap<Piece, usize> {
@@ -128,7 +653,7 @@ impl Game {
         out
     }
 
-    pub fn swap(&mut self, pieces: &[Piece]) -> Option<Vec<Piece>> {
+    pub fn swap(&mut self, pieces: &[Piece]) -> Result<Vec<Piece>, MoveError> {
         if pieces.len() <= self.bag.len() {
             let mut out = Vec::new();
             for _ in 0..pieces.len() {
@@ -137,14 +662,14 @@ impl Game {
             for p in pieces.iter() {
                 self.bag.push(*p);
             }
-            self.bag.shuffle(&mut thread_rng());
-            Some(out)
+            self.bag.shuffle(&mut self.rng);
+            Ok(out)
         } else {
-            None
+            Err(MoveError::BagTooSmall(self.bag.len()))
         }
     }
 
-    fn connected(board: &HashMap<(i32, i32), Piece>) -> bool {
+    fn connected(board: &BoardGrid) -> bool {
         let mut todo: Vec<(i32, i32)> =
             board.keys().take(1).cloned().collect();
 
@@ -166,7 +691,7 @@ impl Game {
     // Checks whether the given play is linear and connected
     //
     // The board must already include the pieces in played
-    pub fn is_linear_connected(board: &HashMap<(i32, i32), Piece>,
+    pub fn is_linear_connected(board: &BoardGrid,
                                played: &[(i32, i32)]) -> bool {
         let xmin = played.iter().map(|p| p.0).min().unwrap_or(0);
         let ymin = played.iter().map(|p| p.1).min().unwrap_or(0);
@@ -187,7 +712,7 @@ impl Game {
         true
     }
 
-    fn explore_from<T>(board: &HashMap<(i32, i32), Piece>, f: T)
+    fn explore_from<T>(board: &BoardGrid, f: T)
         -> Vec<(Piece, (i32, i32))>
         where T: Fn(i32) -> (i32, i32)
     {
@@ -203,13 +728,113 @@ impl Game {
                 }
             };
             run(&|i| i);
-            run(&|i| (-i - 1));
+            run(&|i| -i - 1);
             out
     }
 
+    // Checks whether a single row or column is valid, i.e. it has no
+    // duplicate pieces and shares either a shape or a color
+    fn valid_line(pieces: &[(Piece, (i32, i32))]) -> bool {
+        Self::line_error(pieces.iter().map(|(p, _)| *p)).is_none()
+    }
+
+    fn line_error<I>(pieces: I) -> Option<MoveError>
+        where I: IntoIterator<Item=Piece>
+    {
+        // There are at most seven shapes and colors, so sets of them (and of
+        // pieces) fit into bitmasks, which keeps this check cheap
+        let mut shapes = 0u8;
+        let mut colors = 0u8;
+        let mut seen = 0u64;
+        for (shape, color) in pieces {
+            // Detect duplicate pieces
+            let bit = 1u64 << (shape as u64 * 8 + color as u64);
+            if seen & bit != 0 {
+                return Some(MoveError::DuplicateTile);
+            }
+            seen |= bit;
+            shapes |= 1 << shape as u8;
+            colors |= 1 << color as u8;
+        }
+        if shapes.count_ones() == 1 || colors.count_ones() == 1 {
+            None
+        } else {
+            Some(MoveError::MixedLine)
+        }
+    }
+
+    // Returns every legal play that can be made from the given hand,
+    // as lists of pieces and positions (in the same format as
+    // ClientMessage::Play).  Swaps are not included.
+    pub fn legal_moves(board: &BoardGrid, hand: &[Piece])
+        -> Vec<Vec<(Piece, i32, i32)>>
+    {
+        // A line can't contain duplicate pieces, so we only need to
+        // consider each distinct piece in the hand once.
+        let mut pieces = Vec::new();
+        for p in hand {
+            if !pieces.contains(p) {
+                pieces.push(*p);
+            }
+        }
+        pieces.sort();
+
+        // Find every cell + direction from which a play could begin.  Plays
+        // always extend in the +x or +y direction, so we walk backwards from
+        // each cell on the board's frontier to find possible starting points.
+        let mut starts = HashSet::new();
+        if board.is_empty() {
+            for dir in [(1, 0), (0, 1)].iter() {
+                starts.insert(((0, 0), *dir));
+            }
+        }
+        for pos in board.frontier() {
+            for dir in [(1, 0), (0, 1)].iter() {
+                let mut c = *pos;
+                for _ in 0..pieces.len() {
+                    if board.contains_key(&c) {
+                        break;
+                    }
+                    starts.insert((c, *dir));
+                    c = (c.0 - dir.0, c.1 - dir.1);
+                }
+            }
+        }
+
+        let mut search = MoveSearch {
+            board,
+            scratch: board.clone(),
+            used: vec![false; pieces.len()],
+            pieces,
+            play: Vec::new(),
+            out: Vec::new(),
+        };
+        // Sort the starting points so that moves are returned in a
+        // consistent order, which keeps seeded bots reproducible.
+        let mut starts = starts.into_iter().collect::<Vec<_>>();
+        starts.sort();
+        for (pos, dir) in starts.into_iter() {
+            search.run(pos, dir);
+        }
+        search.out
+    }
+
+    // Returns the longest line that can be made from the given hand, as a
+    // play on an empty board.  The game starts with whoever has the longest
+    // line; ties go to the first line found, so the choice is reproducible.
+    pub fn opening_line(hand: &[Piece]) -> Vec<(Piece, i32, i32)> {
+        let mut best = Vec::new();
+        for play in Self::legal_moves(&BoardGrid::new(), hand) {
+            if play.len() > best.len() {
+                best = play;
+            }
+        }
+        best
+    }
+
     // Checks whether the given board is valid,
     // returning a vec of invalid piece locations
-    pub fn invalid(board: &HashMap<(i32, i32), Piece>) -> HashSet<(i32, i32)> {
+    pub fn invalid(board: &BoardGrid) -> HashSet<(i32, i32)> {
         // The empty board has no invalid pieces, by definition
         if board.is_empty() {
             return HashSet::new();
@@ -226,21 +851,6 @@ impl Game {
 
         let mut out = HashSet::new();
 
-        let check = |pieces: &[(Piece, (i32, i32))]| -> bool {
-            let mut seen_colors = HashSet::new();
-            let mut seen_shapes = HashSet::new();
-            let mut seen_pieces = HashSet::new();
-            for (piece, _pos) in pieces {
-                // Detect duplicate pieces
-                if !seen_pieces.insert(*piece) {
-                    return false;
-                }
-                seen_colors.insert(piece.0);
-                seen_shapes.insert(piece.1);
-            }
-            seen_colors.len() == 1 || seen_shapes.len() == 1
-        };
-
         // Check that each row and column contains valid pieces
         for (x, y) in todo.into_iter() {
             if !checked_h.contains(&(x, y)) {
@@ -248,7 +858,7 @@ impl Game {
                 for (_, c) in row.iter() {
                     checked_h.insert(*c);
                 }
-                if !check(&row) {
+                if !Self::valid_line(&row) {
                     for (_, c) in row.into_iter() {
                         out.insert(c);
                     }
@@ -259,7 +869,7 @@ impl Game {
                 for (_, c) in col.iter() {
                     checked_v.insert(*c);
                 }
-                if !check(&col) {
+                if !Self::valid_line(&col) {
                     for (_, c) in col.into_iter() {
                         out.insert(c);
                     }
@@ -270,22 +880,433 @@ impl Game {
     }
 }
 
+// Depth-first search state used by Game::legal_moves
+struct MoveSearch<'a> {
+    board: &'a BoardGrid,
+    scratch: BoardGrid,
+    pieces: Vec<Piece>,
+    used: Vec<bool>,
+    play: Vec<(Piece, i32, i32)>,
+    out: Vec<Vec<(Piece, i32, i32)>>,
+}
+
+impl<'a> MoveSearch<'a> {
+    // Tries every unused piece at the given position, recording legal plays
+    // and recursing to the next empty cell along the direction of play.
+    fn run(&mut self, pos: (i32, i32), dir: (i32, i32)) {
+        for i in 0..self.pieces.len() {
+            if self.used[i] {
+                continue;
+            }
+            let piece = self.pieces[i];
+            self.scratch.insert(pos, piece);
+
+            // Only the row and column through the new piece can be affected
+            // by placing it, so those are the only lines that we check.
+            let row = Game::explore_from(&self.scratch, |j| (pos.0 + j, pos.1));
+            let col = Game::explore_from(&self.scratch, |j| (pos.0, pos.1 + j));
+            if Game::valid_line(&row) && Game::valid_line(&col) {
+                self.used[i] = true;
+                self.play.push((piece, pos.0, pos.1));
+
+                // Single-piece plays are found in both directions, so we only
+                // record them when searching along the x axis.
+                if (self.play.len() > 1 || dir.0 == 1) && self.touches_board() {
+                    let played = self.play.iter()
+                        .map(|(_, x, y)| (*x, *y))
+                        .collect::<Vec<_>>();
+                    if Game::is_linear_connected(&self.scratch, &played) {
+                        self.out.push(self.play.clone());
+                    }
+                }
+
+                // Skip over pieces that are already on the board, since a
+                // play is allowed to extend through them.
+                let mut next = (pos.0 + dir.0, pos.1 + dir.1);
+                while self.scratch.contains_key(&next) {
+                    next = (next.0 + dir.0, next.1 + dir.1);
+                }
+                self.run(next, dir);
+
+                self.play.pop();
+                self.used[i] = false;
+            }
+            self.scratch.remove(&pos);
+        }
+    }
+
+    // Checks whether the current play is next to a piece on the original
+    // board (which is always true for the first play of the game)
+    fn touches_board(&self) -> bool {
+        self.board.is_empty() ||
+            self.play.iter().any(|(_, x, y)| self.board.on_frontier(&(*x, *y)))
+    }
+}
+
 impl Default for Game {
     fn default() -> Game {
-        use Color::*;
-        use Shape::*;
-        let mut bag = Vec::new();
-        for c in &[Orange, Yellow, Green, Red, Blue, Purple] {
-            for s in &[Clover, Star, Square, Diamond, Cross, Circle] {
-                for _ in 0..3 {
-                    bag.push((*s, *c));
+        Game::new(thread_rng().gen())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Color::*;
+    use Shape::*;
+
+    fn board(pieces: &[(Piece, i32, i32)]) -> BoardGrid {
+        pieces.iter().map(|(p, x, y)| ((*x, *y), *p)).collect()
+    }
+
+    #[test]
+    fn standard_rules_are_valid() {
+        for kinds in RuleSet::MIN_KINDS..=RuleSet::MAX_KINDS {
+            let rules = RuleSet::for_kinds(kinds);
+            assert_eq!(rules.is_valid(), Ok(()), "{} kinds", kinds);
+            assert_eq!(rules.qwirkle_bonus, kinds as u32);
+            assert_eq!(Game::all_pieces(&rules).len(), kinds * kinds * 3);
+        }
+        assert!(RuleSet::for_kinds(RuleSet::MIN_KINDS - 1).is_valid().is_err());
+        assert!(RuleSet::for_kinds(RuleSet::MAX_KINDS + 1).is_valid().is_err());
+    }
+
+    #[test]
+    fn rules_at_their_limits() {
+        let d = RuleSet::default();
+        let small = RuleSet::for_kinds(3);
+        let table = [
+            (RuleSet { hand_size: 0, ..d }, false),
+            (RuleSet { hand_size: RuleSet::MAX_HAND_SIZE, ..d }, true),
+            (RuleSet { hand_size: RuleSet::MAX_HAND_SIZE + 1, ..d }, false),
+            (RuleSet { copies: 0, ..d }, false),
+            (RuleSet { copies: RuleSet::MAX_COPIES, ..d }, true),
+            (RuleSet { copies: RuleSet::MAX_COPIES + 1, ..d }, false),
+            (RuleSet { qwirkle_bonus: RuleSet::MAX_BONUS, ..d }, true),
+            (RuleSet { qwirkle_bonus: RuleSet::MAX_BONUS + 1, ..d }, false),
+            (RuleSet { end_bonus: 0, ..d }, true),
+            (RuleSet { end_bonus: RuleSet::MAX_BONUS + 1, ..d }, false),
+            (RuleSet { turn_secs: RuleSet::MAX_TURN_SECS, ..d }, true),
+            (RuleSet { turn_secs: RuleSet::MAX_TURN_SECS + 1, ..d }, false),
+            (RuleSet { clock_secs: RuleSet::MAX_CLOCK_SECS + 1, ..d }, false),
+            (RuleSet { increment_secs: RuleSet::MAX_INCREMENT_SECS + 1, ..d },
+             false),
+            (RuleSet { max_seats: 0, ..d }, false),
+            (RuleSet { max_seats: RuleSet::MAX_SEATS, ..d }, true),
+            (RuleSet { max_seats: RuleSet::MAX_SEATS + 1, ..d }, false),
+
+            // The 3x3 set has 27 pieces, which is just enough for four
+            // hands of six; one more seat or piece per hand is too many
+            (small, true),
+            (RuleSet { max_seats: 5, ..small }, false),
+            (RuleSet { hand_size: 7, ..small }, false),
+            (RuleSet { copies: 2, ..small }, false),
+            (RuleSet { max_seats: 3, hand_size: 8, copies: 3, ..small }, true),
+            (RuleSet { max_seats: 2, copies: 1, hand_size: 4, ..small }, true),
+            (RuleSet { max_seats: 3, copies: 1, hand_size: 4, ..small }, false),
+        ];
+        for (rules, ok) in table.iter() {
+            assert_eq!(rules.is_valid().is_ok(), *ok, "{:?}", rules);
+        }
+    }
+
+    // Puts each play's pieces in a fixed order, so that plays can be
+    // compared regardless of which direction they were found in
+    fn sorted(mut moves: Vec<Vec<(Piece, i32, i32)>>)
+        -> Vec<Vec<(Piece, i32, i32)>>
+    {
+        for m in moves.iter_mut() {
+            m.sort();
+        }
+        moves.sort();
+        moves
+    }
+
+    #[test]
+    fn legal_moves_on_empty_board() {
+        let hand = [(Cross, Orange), (Cross, Red), (Star, Blue),
+                    (Cross, Orange)];
+        let moves = Game::legal_moves(&BoardGrid::new(), &hand);
+
+        // The blue star doesn't share anything with the crosses, so it's
+        // only ever played on its own
+        let singles = moves.iter().filter(|m| m.len() == 1).count();
+        assert_eq!(singles, 3);
+        assert!(moves.iter().all(|m| m.len() == 1 ||
+            m.iter().all(|(p, _, _)| p.0 == Cross)));
+
+        // The two crosses can go in either order, in a row or a column, and
+        // the duplicate orange cross is never played twice
+        let pairs = moves.iter().filter(|m| m.len() == 2).collect::<Vec<_>>();
+        assert_eq!(pairs.len(), 4);
+        assert!(moves.iter().all(|m| m.len() <= 2));
+        assert!(moves.iter().all(|m| m[0].1 == 0 && m[0].2 == 0));
+    }
+
+    #[test]
+    fn legal_moves_extend_through_board() {
+        let b = board(&[((Circle, Orange), 1, 0)]);
+        let hand = [(Clover, Orange), (Star, Orange)];
+        let moves = sorted(Game::legal_moves(&b, &hand));
+
+        // Pieces on either side of the circle, which the play passes through
+        let through = vec![((Clover, Orange), 0, 0), ((Star, Orange), 2, 0)];
+        assert!(moves.contains(&through));
+        assert!(moves.contains(&vec![((Clover, Orange), 2, 0),
+                                     ((Star, Orange), 0, 0)]));
+
+        // Both pieces on one side is also fine, but a gap isn't
+        assert!(moves.contains(&vec![((Clover, Orange), 2, 0),
+                                     ((Star, Orange), 3, 0)]));
+        assert!(!moves.contains(&vec![((Clover, Orange), 2, 0),
+                                      ((Star, Orange), 4, 0)]));
+    }
+
+    #[test]
+    fn legal_moves_are_unique_and_valid() {
+        // A board from partway through a game, which has plays going in
+        // both directions from several places
+        let b = board(&[((Cross, Orange), 0, 0), ((Cross, Red), 0, 1),
+                        ((Cross, Blue), 0, 2), ((Star, Blue), 1, 2),
+                        ((Circle, Orange), 1, 0), ((Circle, Green), 1, -1),
+                        ((Circle, Yellow), 1, -2)]);
+        let hand = [(Cross, Green), (Star, Green), (Circle, Blue),
+                    (Square, Blue), (Star, Red), (Cross, Green)];
+        let moves = Game::legal_moves(&b, &hand);
+        assert!(moves.iter().any(|m| m.len() > 1));
+
+        let unique = sorted(moves.clone());
+        let mut deduped = unique.clone();
+        deduped.dedup();
+        assert_eq!(unique, deduped);
+
+        for m in moves.iter() {
+            assert_eq!(Game::validate(&b, m), Ok(()), "{:?}", m);
+
+            // Pieces are only played as many times as they're in the hand
+            let mut left = hand.to_vec();
+            for (p, _, _) in m.iter() {
+                let i = left.iter().position(|q| q == p).unwrap();
+                left.swap_remove(i);
+            }
+        }
+    }
+
+    #[test]
+    fn legal_moves_none_possible() {
+        // The blue star matches nothing on the board, and the orange cross
+        // would be a duplicate of the piece that's there
+        let b = board(&[((Cross, Orange), 0, 0)]);
+        let hand = [(Star, Blue), (Cross, Orange)];
+        assert!(Game::legal_moves(&b, &hand).is_empty());
+        assert!(Game::legal_moves(&b, &[]).is_empty());
+    }
+
+    // A row of orange pieces with a gap at (3, 0), and a column of circles
+    // hanging off of it
+    fn validate_board() -> BoardGrid {
+        board(&[((Clover, Orange), 0, 0), ((Star, Orange), 1, 0),
+                ((Square, Orange), 2, 0), ((Circle, Orange), 4, 0),
+                ((Circle, Red), 4, 1), ((Circle, Blue), 4, 2)])
+    }
+
+    #[test]
+    fn validate_errors() {
+        let b = validate_board();
+        let check = |ps: &[(Piece, i32, i32)]| Game::validate(&b, ps);
+
+        assert_eq!(check(&[]), Err(MoveError::NoPieces));
+        assert_eq!(check(&[((Cross, Orange), 1, 0)]),
+                   Err(MoveError::Occupied));
+        assert_eq!(check(&[((Cross, Green), 1, 1), ((Cross, Green), 1, 1)]),
+                   Err(MoveError::Occupied));
+        assert_eq!(check(&[((Clover, Red), 1, 1), ((Star, Red), 2, 2)]),
+                   Err(MoveError::NotLinear));
+        assert_eq!(check(&[((Clover, Blue), 1, 2), ((Clover, Red), 3, 2)]),
+                   Err(MoveError::Gap));
+        assert_eq!(check(&[((Clover, Blue), 5, 5)]),
+                   Err(MoveError::Disconnected));
+        assert_eq!(check(&[((Star, Orange), 3, 0)]),
+                   Err(MoveError::DuplicateTile));
+        assert_eq!(check(&[((Cross, Green), 3, 0)]),
+                   Err(MoveError::MixedLine));
+
+        // A piece can fit the line along the play but not the one across it
+        assert_eq!(check(&[((Circle, Red), 4, 3), ((Square, Red), 5, 3)]),
+                   Err(MoveError::DuplicateTile));
+        assert_eq!(check(&[((Star, Green), 4, 3), ((Square, Green), 5, 3)]),
+                   Err(MoveError::MixedLine));
+
+        assert_eq!(check(&[((Diamond, Orange), 3, 0)]), Ok(()));
+        assert_eq!(check(&[((Circle, Green), 4, 3), ((Circle, Yellow), 4, 4)]),
+                   Ok(()));
+    }
+
+    // The old check, which placed the play on a copy of the board and then
+    // rescanned the whole thing
+    fn rescan(board: &BoardGrid, ps: &[(Piece, i32, i32)]) -> bool {
+        let mut b = board.clone();
+        for (p, x, y) in ps {
+            if b.insert((*x, *y), *p).is_some() {
+                return false;
+            }
+        }
+        let cells = ps.iter().map(|(_, x, y)| (*x, *y)).collect::<Vec<_>>();
+        !ps.is_empty() && Game::invalid(&b).is_empty() &&
+            Game::is_linear_connected(&b, &cells)
+    }
+
+    // Plays a seeded game for a while, then checks that validate agrees
+    // with a full rescan on every legal play, on those plays moved around
+    // the board, and on every single piece at every open cell.
+    #[test]
+    fn validate_matches_rescan() {
+        let mut game = Game::new(3);
+        let mut hands = (0..2)
+            .map(|_| game.deal(6).into_iter()
+                .flat_map(|(p, n)| std::iter::repeat_n(p, n))
+                .collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for turn in 0..12 {
+            let hand = &mut hands[turn % 2];
+            let best = Game::legal_moves(&game.board, hand).into_iter()
+                .max_by_key(|m| m.len());
+            if let Some(m) = best {
+                for (p, _, _) in m.iter() {
+                    let i = hand.iter().position(|q| q == p).unwrap();
+                    hand.swap_remove(i);
+                }
+                game.play(&m).unwrap();
+                for (p, n) in game.deal(m.len()) {
+                    hand.extend(std::iter::repeat_n(p, n));
                 }
             }
         }
-        bag.shuffle(&mut thread_rng());
+        let b = &game.board;
+        assert!(b.len() > 15);
 
-        Game {
-            board: HashMap::new(), bag
+        let mut plays = Vec::new();
+        for hand in hands.iter() {
+            plays.extend(Game::legal_moves(b, hand));
+        }
+        let shifted = plays.iter()
+            .flat_map(|m| [(1, 0), (0, 1), (-1, -1), (0, -2)].iter()
+                .map(move |(dx, dy)| m.iter()
+                    .map(|(p, x, y)| (*p, x + dx, y + dy))
+                    .collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        plays.extend(shifted);
+        for pos in b.frontier() {
+            for p in Game::all_pieces(&game.rules).iter().step_by(3) {
+                plays.push(vec![(*p, pos.0, pos.1)]);
+            }
+        }
+
+        let mut ok = 0;
+        for m in plays.iter() {
+            let valid = rescan(b, m);
+            assert_eq!(Game::validate(b, m).is_ok(), valid, "{:?}", m);
+            ok += valid as usize;
         }
+        assert!(ok > 0 && ok < plays.len());
+    }
+
+    fn standing(player: usize, lines: u32, end_bonus: u32) -> Standing {
+        let breakdown = ScoreBreakdown { lines, end_bonus,
+                                         ..ScoreBreakdown::default() };
+        Standing {
+            player,
+            place: 0,
+            score: breakdown.total(),
+            breakdown,
+            tiles_left: if end_bonus > 0 { 0 } else { 3 },
+        }
+    }
+
+    #[test]
+    fn rank_shares_tied_places() {
+        let mut s = vec![standing(0, 10, 0), standing(1, 20, 0),
+                         standing(2, 10, 0), standing(3, 5, 0)];
+        Standing::rank(&mut s);
+        let order = s.iter().map(|s| (s.player, s.place)).collect::<Vec<_>>();
+        assert_eq!(order, vec![(1, 1), (0, 2), (2, 2), (3, 4)]);
+
+        // Everybody tied
+        let mut s = vec![standing(1, 7, 0), standing(0, 7, 0)];
+        Standing::rank(&mut s);
+        let order = s.iter().map(|s| (s.player, s.place)).collect::<Vec<_>>();
+        assert_eq!(order, vec![(0, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn rank_counts_end_bonus() {
+        // Going out can lift a player past someone with more points from
+        // lines, or into a tie with them
+        let mut s = vec![standing(0, 14, 0), standing(1, 10, 6),
+                         standing(2, 8, 6)];
+        Standing::rank(&mut s);
+        let order = s.iter().map(|s| (s.player, s.place)).collect::<Vec<_>>();
+        assert_eq!(order, vec![(1, 1), (0, 2), (2, 2)]);
+        assert_eq!(s[0].score, 16);
+        assert_eq!(s[0].tiles_left, 0);
+    }
+
+    #[test]
+    fn score_single_piece_on_empty_board() {
+        let score = Game::score_play(&BoardGrid::new(), RuleSet::default(),
+                                     &[((Cross, Orange), 0, 0)]);
+        assert_eq!(score, PlayScore::default());
+        assert_eq!(score.breakdown.total(), 0);
+    }
+
+    #[test]
+    fn score_cross_play() {
+        // A row of two orange pieces and a column of two crosses, which a
+        // new orange cross joins at the corner
+        let b = board(&[((Circle, Orange), 1, 0), ((Square, Orange), 2, 0),
+                        ((Cross, Red), 0, 1), ((Cross, Blue), 0, 2)]);
+        let score = Game::score_play(&b, RuleSet::default(),
+                                     &[((Cross, Orange), 0, 0)]);
+        assert_eq!(score.breakdown.total(), 6);
+        assert_eq!(score.breakdown.qwirkles, 0);
+        assert_eq!(score.lines, vec![
+            ScoredLine {
+                cells: vec![(0, 0), (1, 0), (2, 0)],
+                points: 3,
+                qwirkle: false,
+            },
+            ScoredLine {
+                cells: vec![(0, 0), (0, 1), (0, 2)],
+                points: 3,
+                qwirkle: false,
+            },
+        ]);
+
+        // The board itself isn't changed
+        assert_eq!(b.len(), 4);
+    }
+
+    #[test]
+    fn score_qwirkle() {
+        let rules = RuleSet::default();
+        let b = board(&[((Clover, Green), 0, 0), ((Star, Green), 1, 0),
+                        ((Square, Green), 2, 0), ((Diamond, Green), 3, 0)]);
+        let play = [((Cross, Green), 4, 0), ((Circle, Green), 5, 0)];
+        let score = Game::score_play(&b, rules, &play);
+        assert_eq!(score.breakdown.lines, 6);
+        assert_eq!(score.breakdown.qwirkles, 1);
+        assert_eq!(score.breakdown.qwirkle_bonus, rules.qwirkle_bonus);
+        assert_eq!(score.breakdown.total(), 6 + rules.qwirkle_bonus);
+
+        // Both new pieces are in the same line, which is only scored once
+        assert_eq!(score.lines.len(), 1);
+        assert!(score.lines[0].qwirkle);
+        assert_eq!(score.lines[0].points, 6 + rules.qwirkle_bonus);
+        assert_eq!(score.lines[0].cells.len(), 6);
+
+        // Playing it scores the same
+        let mut g = Game::new(0);
+        g.board = b;
+        assert_eq!(g.play(&play), Ok(6 + rules.qwirkle_bonus));
     }
 }
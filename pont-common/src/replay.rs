@@ -0,0 +1,354 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+use crate::{Game, Piece, RuleSet, ServerMessage};
+use crate::board::BoardGrid;
+
+// Bump this whenever the Event or ServerMessage format changes in an
+// incompatible way
+pub const REPLAY_VERSION: u32 = 1;
+
+// Everything that happens in a room, in the order that it happened.
+//
+// Events that touch the bag (dealing, swapping, returning pieces) are
+// recorded in the same order as the matching calls on Game, so that
+// replaying them against a Game built from the same seed rebuilds the
+// exact same bag.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Event {
+    Joined(String),
+    Rejoined(usize),
+    Disconnected(usize),
+    Dealt {
+        player: usize,
+        pieces: Vec<Piece>,
+    },
+    Played {
+        player: usize,
+        pieces: Vec<(Piece, i32, i32)>,
+    },
+    Swapped {
+        player: usize,
+        pieces: Vec<Piece>,
+        dealt: Vec<Piece>,
+    },
+    Passed(usize),
+    // A player's hand was put back into the bag, which was then shuffled
+    Returned {
+        player: usize,
+        pieces: Vec<Piece>,
+    },
+    Scored {
+        player: usize,
+        delta: u32,
+    },
+    Turn(usize),
+    Chat {
+        from: String,
+        message: String,
+    },
+    Ended,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Replay {
+    pub version: u32,
+    pub room_name: String,
+    pub seed: u64,
+    pub rules: RuleSet,
+
+    // Each event is tagged with a timestamp, in milliseconds since the epoch
+    pub events: Vec<(u64, Event)>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Json(serde_json::Error),
+    Bincode(bincode::Error),
+    Version(u32),
+
+    // An event refers to a player who isn't in the replay
+    NoSuchPlayer(usize),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Json(e) => write!(f, "Invalid JSON replay: {}", e),
+            ReplayError::Bincode(e) => write!(f, "Invalid binary replay: {}", e),
+            ReplayError::Version(v) => write!(f,
+                "Unsupported replay version {} (expected {})",
+                v, REPLAY_VERSION),
+            ReplayError::NoSuchPlayer(i) => write!(f,
+                "Invalid replay: there is no player {}", i),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Replay {
+    pub fn new(room_name: &str, seed: u64, rules: RuleSet) -> Replay {
+        Replay {
+            version: REPLAY_VERSION,
+            room_name: room_name.to_string(),
+            seed,
+            rules,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, time: u64, event: Event) {
+        self.events.push((time, event));
+    }
+
+    pub fn to_json(&self) -> Result<String, ReplayError> {
+        serde_json::to_string(self).map_err(ReplayError::Json)
+    }
+
+    pub fn from_json(s: &str) -> Result<Replay, ReplayError> {
+        serde_json::from_str::<Replay>(s)
+            .map_err(ReplayError::Json)
+            .and_then(Self::check_version)
+    }
+
+    pub fn to_bincode(&self) -> Result<Vec<u8>, ReplayError> {
+        bincode::serialize(self).map_err(ReplayError::Bincode)
+    }
+
+    pub fn from_bincode(b: &[u8]) -> Result<Replay, ReplayError> {
+        bincode::deserialize::<Replay>(b)
+            .map_err(ReplayError::Bincode)
+            .and_then(Self::check_version)
+    }
+
+    fn check_version(r: Replay) -> Result<Replay, ReplayError> {
+        if r.version == REPLAY_VERSION {
+            Ok(r)
+        } else {
+            Err(ReplayError::Version(r.version))
+        }
+    }
+
+    // Returns the index of every event that starts a new turn
+    pub fn turns(&self) -> Vec<usize> {
+        self.events.iter()
+            .enumerate()
+            .filter(|(_i, (_t, e))| matches!(e, Event::Turn(_)))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // Rebuilds the state of the room after the first n events
+    pub fn state_at(&self, n: usize) -> Result<ReplayState, ReplayError> {
+        let mut state = ReplayState::new(self.rules, self.seed);
+        for (_t, e) in self.events.iter().take(n) {
+            state.apply(e)?;
+        }
+        Ok(state)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A recording of every message that a single client received from the
+// server, which is what the client saves and plays back.  Unlike a Replay,
+// this only contains what that player was allowed to see.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Recording {
+    pub version: u32,
+
+    // Each message is tagged with a timestamp, in milliseconds since the epoch
+    pub messages: Vec<(u64, ServerMessage)>,
+}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording {
+            version: REPLAY_VERSION,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, time: u64, msg: ServerMessage) {
+        self.messages.push((time, msg));
+    }
+
+    // Rebuilds the board as it stands at the end of the recording, which
+    // should match what the player last saw
+    pub fn board(&self) -> BoardGrid {
+        let mut out = BoardGrid::new();
+        for (_t, msg) in self.messages.iter() {
+            match msg {
+                ServerMessage::JoinedRoom { board, .. } |
+                ServerMessage::Spectating { board, .. } =>
+                    out = board.iter().cloned().collect(),
+                ServerMessage::Played(pieces) =>
+                    for (p, x, y) in pieces {
+                        out.insert((*x, *y), *p);
+                    },
+                _ => (),
+            }
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> Result<String, ReplayError> {
+        serde_json::to_string(self).map_err(ReplayError::Json)
+    }
+
+    pub fn from_json(s: &str) -> Result<Recording, ReplayError> {
+        let r = serde_json::from_str::<Recording>(s)
+            .map_err(ReplayError::Json)?;
+        if r.version == REPLAY_VERSION {
+            Ok(r)
+        } else {
+            Err(ReplayError::Version(r.version))
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub struct ReplayPlayer {
+    pub name: String,
+    pub score: u32,
+    pub hand: Vec<Piece>,
+    pub connected: bool,
+}
+
+impl ReplayPlayer {
+    fn remove(&mut self, pieces: &[Piece]) {
+        for p in pieces {
+            if let Some(i) = self.hand.iter().position(|q| q == p) {
+                self.hand.remove(i);
+            }
+        }
+    }
+}
+
+// The state of a room at some point in a replay
+#[derive(Debug)]
+pub struct ReplayState {
+    pub game: Game,
+    pub players: Vec<ReplayPlayer>,
+    pub active_player: usize,
+    pub ended: bool,
+}
+
+impl ReplayState {
+    pub fn new(rules: RuleSet, seed: u64) -> ReplayState {
+        ReplayState {
+            game: Game::with_rules(rules, seed),
+            players: Vec::new(),
+            active_player: 0,
+            ended: false,
+        }
+    }
+
+    // Applies a single event.  Replays may come from a file, so events that
+    // refer to players who never joined are turned down rather than trusted.
+    pub fn apply(&mut self, event: &Event) -> Result<(), ReplayError> {
+        match event {
+            Event::Joined(name) => self.players.push(ReplayPlayer {
+                name: name.clone(),
+                score: 0,
+                hand: Vec::new(),
+                connected: true,
+            }),
+            Event::Rejoined(i) => self.player(*i)?.connected = true,
+            Event::Disconnected(i) => self.player(*i)?.connected = false,
+            Event::Dealt { player, pieces } => {
+                self.player(*player)?.hand.extend(pieces.iter());
+                self.game.deal(pieces.len());
+            },
+            Event::Played { player, pieces } => {
+                let ps = pieces.iter().map(|p| p.0).collect::<Vec<_>>();
+                self.player(*player)?.remove(&ps);
+
+                // Recorded moves were checked when they were made
+                let _ = self.game.play(pieces);
+            },
+            Event::Swapped { player, pieces, dealt } => {
+                let p = self.player(*player)?;
+                p.remove(pieces);
+                p.hand.extend(dealt.iter());
+                let _ = self.game.swap(pieces);
+            },
+            Event::Returned { player, pieces } => {
+                self.player(*player)?.remove(pieces);
+                self.game.bag.extend(pieces.iter());
+                self.game.shuffle();
+            },
+            Event::Scored { player, delta } =>
+                self.player(*player)?.score += delta,
+            Event::Turn(i) => {
+                self.player(*i)?;
+                self.active_player = *i;
+            },
+            Event::Passed(i) => {
+                self.player(*i)?;
+            },
+            Event::Ended => self.ended = true,
+            Event::Chat { .. } => (),
+        }
+        Ok(())
+    }
+
+    fn player(&mut self, i: usize) -> Result<&mut ReplayPlayer, ReplayError> {
+        self.players.get_mut(i).ok_or(ReplayError::NoSuchPlayer(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Color::*, Shape::*};
+
+    // What the client records over a couple of turns, including its own
+    // play (which the server only acknowledges with MoveAccepted)
+    #[test]
+    fn recording_rebuilds_board() {
+        let mut r = Recording::new();
+        r.push(0, ServerMessage::JoinedRoom {
+            room_name: "room".to_string(),
+            players: vec![("alice".to_string(), 0, true),
+                          ("bob".to_string(), 0, true)],
+            active_player: 0,
+            player_index: 0,
+            board: vec![((0, 0), (Cross, Orange))],
+            pieces: vec![(Circle, Orange), (Square, Orange)],
+            rules: RuleSet::default(),
+            started: true,
+            token: String::new(),
+        });
+        let ours = vec![((Circle, Orange), 1, 0), ((Square, Orange), 2, 0)];
+        r.push(1, ServerMessage::Played(ours));
+        r.push(1, ServerMessage::MoveAccepted(vec![(Star, Red)]));
+
+        // Bob's play only connects to the board through our pieces
+        let theirs = vec![((Square, Red), 2, 1), ((Square, Blue), 2, 2)];
+        let expected: BoardGrid = vec![((0, 0), (Cross, Orange)),
+                                       ((1, 0), (Circle, Orange)),
+                                       ((2, 0), (Square, Orange))].into();
+        assert_eq!(r.board(), expected);
+        assert_eq!(Game::validate(&r.board(), &theirs), Ok(()));
+
+        r.push(2, ServerMessage::Played(theirs));
+        let board = r.board();
+        assert_eq!(board.len(), 5);
+        assert_eq!(board.get(&(2, 0)), Some(&(Square, Orange)));
+        assert_eq!(board.get(&(2, 2)), Some(&(Square, Blue)));
+    }
+
+    #[test]
+    fn state_at_rejects_unknown_players() {
+        let mut r = Replay::new("room", 0, RuleSet::default());
+        r.push(0, Event::Joined("alice".to_string()));
+        r.push(0, Event::Dealt { player: 0, pieces: vec![(Cross, Orange)] });
+        r.push(0, Event::Scored { player: 3, delta: 10 });
+        assert_eq!(r.state_at(2).unwrap().players[0].hand.len(), 1);
+        assert!(matches!(r.state_at(3), Err(ReplayError::NoSuchPlayer(3))));
+    }
+}
@@ -0,0 +1,448 @@
+use serde::{Serialize, Deserialize};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use crate::{Difficulty, Game, Piece, RuleSet};
+use crate::board::BoardGrid;
+
+// Everything that a single player is allowed to know about the game
+pub struct GameView<'a> {
+    pub rules: RuleSet,
+    pub board: &'a BoardGrid,
+    pub hand: &'a [Piece],
+    pub bag_size: usize,
+    pub scores: &'a [u32],
+    pub player_index: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Move {
+    Play(Vec<(Piece, i32, i32)>),
+    Swap(Vec<Piece>),
+    Pass,
+}
+
+pub trait Strategy {
+    fn choose(&mut self, view: &GameView) -> Move;
+}
+
+impl Difficulty {
+    // Builds a fresh strategy for a bot at this difficulty level
+    pub fn strategy(self) -> Box<dyn Strategy> {
+        match self {
+            Difficulty::Easy => Box::new(Random::new(rand::random())),
+            Difficulty::Medium => Box::new(Defensive),
+            Difficulty::Hard => Box::new(MonteCarlo::new(8, rand::random())),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type Play = Vec<(Piece, i32, i32)>;
+
+// Returns the best-scoring play and its score, if any play is possible.
+// Ties are broken in favor of playing more pieces.
+fn best_play(rules: RuleSet, board: &BoardGrid,
+             hand: &[Piece]) -> Option<(u32, Play)>
+{
+    Game::legal_moves(board, hand).into_iter()
+        .map(|m| (Game::score_play(board, rules, &m).breakdown.total(), m))
+        .max_by_key(|(s, m)| (*s, m.len()))
+}
+
+// What to do when there are no legal plays: swap as much of the hand as the
+// bag allows, or pass if the bag is empty.  If the bag is running low, then
+// we hold on to the longest line in the hand (which could be played once
+// the board opens up), and swap everything else first.
+fn fallback(view: &GameView) -> Move {
+    let n = view.hand.len().min(view.bag_size);
+    if n == 0 {
+        return Move::Pass;
+    }
+    let mut rest = view.hand.to_vec();
+    let mut line = Vec::new();
+    for (p, _, _) in Game::opening_line(view.hand) {
+        let i = rest.iter().position(|q| *q == p).unwrap();
+        line.push(rest.swap_remove(i));
+    }
+    rest.sort();
+    line.sort();
+    rest.extend(line);
+    rest.truncate(n);
+    Move::Swap(rest)
+}
+
+// Returns every piece that this player hasn't seen, i.e. pieces that are
+// either in the bag or in another player's hand.
+fn unseen(view: &GameView) -> Vec<Piece> {
+    let mut out = Game::all_pieces(&view.rules);
+    for p in view.board.values().chain(view.hand.iter()) {
+        if let Some(i) = out.iter().position(|q| q == p) {
+            out.swap_remove(i);
+        }
+    }
+    out.sort();
+    out
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Plays a random legal move
+pub struct Random {
+    rng: StdRng,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random { rng: StdRng::seed_from_u64(seed) }
+    }
+}
+
+impl Strategy for Random {
+    fn choose(&mut self, view: &GameView) -> Move {
+        let moves = Game::legal_moves(view.board, view.hand);
+        match moves.choose(&mut self.rng) {
+            Some(m) => Move::Play(m.clone()),
+            None => fallback(view),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Plays whichever move scores the most points this turn
+pub struct Greedy;
+
+impl Strategy for Greedy {
+    fn choose(&mut self, view: &GameView) -> Move {
+        match best_play(view.rules, view.board, view.hand) {
+            Some((_, m)) => Move::Play(m),
+            None => fallback(view),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Plays greedily, but avoids leaving lines that are one piece short of a
+// Qwirkle, which another player could complete.
+pub struct Defensive;
+
+impl Defensive {
+    // Points that an opponent would score by completing a Qwirkle
+    fn penalty(rules: RuleSet) -> i64 {
+        (rules.kinds as u32 + rules.qwirkle_bonus) as i64
+    }
+
+    // Returns the number of lines touched by this play that end up one piece
+    // short of a Qwirkle, where the missing piece could still be drawn.
+    fn open_lines(rules: RuleSet, board: &BoardGrid,
+                  play: &[(Piece, i32, i32)], unseen: &[Piece]) -> usize
+    {
+        let short = rules.kinds - 1;
+        let mut board = board.clone();
+        for (p, x, y) in play {
+            board.insert((*x, *y), *p);
+        }
+
+        let mut lines = Vec::new();
+        for (_, x, y) in play {
+            for line in [Game::explore_from(&board, |i| (*x + i, *y)),
+                         Game::explore_from(&board, |i| (*x, *y + i))].iter()
+            {
+                let mut line = line.iter().map(|(p, c)| (*c, *p))
+                    .collect::<Vec<_>>();
+                line.sort_by_key(|(c, _)| *c);
+                if line.len() == short && !lines.contains(&line) {
+                    lines.push(line);
+                }
+            }
+        }
+
+        lines.iter().filter(|line| {
+            // The line must have an open cell at one end
+            let (a, b) = (line[0].0, line[short - 1].0);
+            let n = short as i32 - 1;
+            let (dx, dy) = ((b.0 - a.0) / n, (b.1 - a.1) / n);
+            let open = !board.contains_key(&(a.0 - dx, a.1 - dy)) ||
+                       !board.contains_key(&(b.0 + dx, b.1 + dy));
+
+            // The line's missing piece shares the line's common attribute
+            // and differs from every piece already in the line.
+            let (p0, p1) = (line[0].1, line[1].1);
+            let completes = |q: &Piece| if p0.0 == p1.0 {
+                q.0 == p0.0 && line.iter().all(|(_, p)| p.1 != q.1)
+            } else {
+                q.1 == p0.1 && line.iter().all(|(_, p)| p.0 != q.0)
+            };
+            open && unseen.iter().any(completes)
+        }).count()
+    }
+}
+
+impl Strategy for Defensive {
+    fn choose(&mut self, view: &GameView) -> Move {
+        let unseen = unseen(view);
+        let best = Game::legal_moves(view.board, view.hand).into_iter()
+            .max_by_key(|m| {
+                let s = Game::score_play(view.board, view.rules, m)
+                    .breakdown.total() as i64;
+                let n = Self::open_lines(view.rules, view.board, m, &unseen);
+                (s - n as i64 * Self::penalty(view.rules), m.len())
+            });
+        match best {
+            Some(m) => Move::Play(m),
+            None => fallback(view),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Determinized Monte-Carlo search.  For each of the best-scoring candidate
+// plays, we repeatedly deal the unseen pieces at random between the bag and
+// the other players' hands, then estimate the value of the play as its score,
+// minus the next player's best reply, plus our own best follow-up.
+pub struct MonteCarlo {
+    samples: usize,
+    candidates: usize,
+    rng: StdRng,
+}
+
+impl MonteCarlo {
+    pub fn new(samples: usize, seed: u64) -> MonteCarlo {
+        MonteCarlo {
+            samples,
+            candidates: 4,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    fn evaluate(&mut self, view: &GameView, unseen: &mut [Piece],
+                play: &[(Piece, i32, i32)], score: u32) -> f64
+    {
+        let mut board = view.board.clone();
+        for (p, x, y) in play {
+            board.insert((*x, *y), *p);
+        }
+        let mut hand = view.hand.to_vec();
+        for (p, _, _) in play {
+            if let Some(i) = hand.iter().position(|q| q == p) {
+                hand.swap_remove(i);
+            }
+        }
+
+        // Pieces that aren't in the bag must be in another player's hand
+        let in_hands = unseen.len().saturating_sub(view.bag_size);
+        let opponents = view.scores.len() > 1;
+
+        let mut total = 0.0;
+        for _ in 0..self.samples {
+            unseen.shuffle(&mut self.rng);
+            let (hands, bag) = unseen.split_at(in_hands);
+
+            let mut board = board.clone();
+            if opponents {
+                let theirs = &hands[..hands.len().min(view.hand.len())];
+                if let Some((s, m)) = best_play(view.rules, &board, theirs) {
+                    total -= s as f64;
+                    for (p, x, y) in m {
+                        board.insert((x, y), p);
+                    }
+                }
+            }
+
+            let mut ours = hand.clone();
+            ours.extend(bag.iter().take(play.len()));
+            if let Some((s, _)) = best_play(view.rules, &board, &ours) {
+                total += s as f64;
+            }
+        }
+        score as f64 + total / self.samples.max(1) as f64
+    }
+}
+
+impl Strategy for MonteCarlo {
+    fn choose(&mut self, view: &GameView) -> Move {
+        let mut moves = Game::legal_moves(view.board, view.hand).into_iter()
+            .map(|m| (Game::score_play(view.board, view.rules, &m)
+                          .breakdown.total(), m))
+            .collect::<Vec<_>>();
+        if moves.is_empty() {
+            return fallback(view);
+        }
+
+        // Only search the most promising plays, to keep things fast
+        moves.sort_by_key(|(s, m)| std::cmp::Reverse((*s, m.len())));
+        moves.truncate(self.candidates);
+
+        // If there's no uncertainty left, then the search won't help
+        let mut unseen = unseen(view);
+        if unseen.is_empty() || moves.len() == 1 {
+            return Move::Play(moves.swap_remove(0).1);
+        }
+
+        // Break near-ties randomly, so that we don't always play the same
+        // opening when several are equally good.
+        let mut best = None;
+        let mut best_value = f64::NEG_INFINITY;
+        for (s, m) in moves.into_iter() {
+            let v = self.evaluate(view, &mut unseen, &m, s) +
+                    self.rng.gen_range(0.0, 1e-3);
+            if v > best_value {
+                best_value = v;
+                best = Some(m);
+            }
+        }
+        Move::Play(best.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Color::*, Shape::*};
+
+    fn view<'a>(board: &'a BoardGrid, hand: &'a [Piece],
+                bag_size: usize) -> GameView<'a>
+    {
+        GameView {
+            rules: RuleSet::default(),
+            board,
+            hand,
+            bag_size,
+            scores: &[0, 0],
+            player_index: 0,
+        }
+    }
+
+    fn score(board: &BoardGrid, play: &[(Piece, i32, i32)]) -> u32 {
+        Game::score_play(board, RuleSet::default(), play).breakdown.total()
+    }
+
+    #[test]
+    fn greedy_prefers_score_then_length() {
+        // Extending the row with the diamond and laying a pair of clovers
+        // alongside it are both worth 4 points, but the clovers use more
+        // pieces
+        let board: BoardGrid = vec![((0, 0), (Clover, Orange)),
+                                    ((1, 0), (Star, Orange)),
+                                    ((2, 0), (Square, Orange))].into();
+        let hand = [(Clover, Red), (Clover, Blue), (Diamond, Orange)];
+        let moves = Game::legal_moves(&board, &hand);
+        let top = moves.iter().map(|m| score(&board, m)).max();
+        assert_eq!(top, Some(4));
+        assert!(moves.iter().any(|m| m.len() == 1 && score(&board, m) == 4));
+
+        match Greedy.choose(&view(&board, &hand, 10)) {
+            Move::Play(m) => {
+                assert_eq!(score(&board, &m), 4);
+                assert_eq!(m.len(), 2);
+            },
+            m => panic!("Expected a play, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn fallback_swaps_or_passes() {
+        let board = BoardGrid::new();
+        let hand = [(Cross, Orange), (Cross, Red), (Star, Green),
+                    (Cross, Blue), (Clover, Yellow), (Cross, Orange)];
+
+        // With a big enough bag, the whole hand goes back
+        match fallback(&view(&board, &hand, 10)) {
+            Move::Swap(mut s) => {
+                let mut h = hand.to_vec();
+                h.sort();
+                s.sort();
+                assert_eq!(s, h);
+            },
+            m => panic!("Expected a swap, got {:?}", m),
+        }
+
+        // Otherwise, the line of crosses is kept for as long as possible
+        assert_eq!(fallback(&view(&board, &hand, 3)), Move::Swap(vec![
+            (Clover, Yellow), (Star, Green), (Cross, Orange)]));
+        assert_eq!(fallback(&view(&board, &hand, 4)), Move::Swap(vec![
+            (Clover, Yellow), (Star, Green), (Cross, Orange),
+            (Cross, Orange)]));
+        assert_eq!(fallback(&view(&board, &hand, 0)), Move::Pass);
+    }
+
+    #[test]
+    fn defensive_denies_qwirkle() {
+        // Adding the orange cross would score 5, but would leave a line that
+        // any orange circle could complete
+        let board: BoardGrid = vec![((0, 0), (Clover, Orange)),
+                                    ((1, 0), (Star, Orange)),
+                                    ((2, 0), (Square, Orange)),
+                                    ((3, 0), (Diamond, Orange))].into();
+        let hand = [(Cross, Orange), (Clover, Red)];
+        let v = view(&board, &hand, 10);
+        let cross = [((Cross, Orange), 4, 0)];
+        assert_eq!(Greedy.choose(&v), Move::Play(cross.to_vec()));
+        let rules = RuleSet::default();
+        match Defensive.choose(&v) {
+            Move::Play(m) => assert_eq!(
+                Defensive::open_lines(rules, &board, &m, &unseen(&v)), 0),
+            m => panic!("Expected a play, got {:?}", m),
+        }
+
+        // Once every orange circle has been seen, the line is safe
+        assert_eq!(Defensive::open_lines(rules, &board, &cross,
+                                         &unseen(&v)), 1);
+        assert_eq!(Defensive::open_lines(rules, &board, &cross,
+                                         &[(Circle, Red)]), 0);
+    }
+
+    // Plays a short seeded game between Monte-Carlo and random bots,
+    // checking that every move that they make is allowed
+    #[test]
+    fn bots_make_legal_moves() {
+        let mut game = Game::new(5);
+        let mut bots: Vec<Box<dyn Strategy>> =
+            vec![Box::new(MonteCarlo::new(1, 1)), Box::new(Random::new(2))];
+        let mut hands = (0..2)
+            .map(|_| game.deal(6).into_iter()
+                .flat_map(|(p, n)| std::iter::repeat_n(p, n))
+                .collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for turn in 0..8 {
+            let i = turn % 2;
+            let v = GameView {
+                rules: game.rules,
+                board: &game.board,
+                hand: &hands[i],
+                bag_size: game.bag.len(),
+                scores: &[0, 0],
+                player_index: i,
+            };
+            let (pieces, dealt) = match bots[i].choose(&v) {
+                Move::Play(m) => {
+                    assert_eq!(game.check_play(&m), Ok(()), "{:?}", m);
+                    game.play(&m).unwrap();
+                    let pieces = m.iter().map(|(p, _, _)| *p).collect();
+                    (pieces, game.deal(m.len()).into_iter()
+                        .flat_map(|(p, n)| std::iter::repeat_n(p, n))
+                        .collect())
+                },
+                Move::Swap(s) => {
+                    let dealt = game.swap(&s).unwrap();
+                    (s, dealt)
+                },
+                Move::Pass => (Vec::new(), Vec::new()),
+            };
+            let hand = &mut hands[i];
+            for p in pieces.iter() {
+                let j = hand.iter().position(|q| q == p)
+                    .expect("Bot used a piece that it doesn't have");
+                hand.swap_remove(j);
+            }
+            hand.extend(dealt);
+        }
+        assert!(!game.board.is_empty());
+    }
+}
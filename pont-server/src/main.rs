@@ -1,10 +1,13 @@
 use std::{
     collections::HashMap,
     env,
+    ffi::OsStr,
     io::Error as IoError,
+    fs,
     net::{TcpStream, TcpListener, SocketAddr},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use rand::Rng;
 use log::{error, warn, info, debug, trace};
@@ -16,54 +19,155 @@ use futures::sink::SinkExt;
 use futures::channel::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
 
 use anyhow::Result;
+use serde::{Serialize, Deserialize};
 use tungstenite::Message as WebsocketMessage;
 use async_tungstenite::WebSocketStream;
 use smol::{Async, Task, Timer};
 
-use pont_common::{ClientMessage, ServerMessage, Game, Piece};
+use pont_common::{ClientMessage, ServerMessage, Difficulty, Game, MoveError,
+                  Piece, RuleSet, ScoreBreakdown, Standing};
+use pont_common::replay::{Event, Replay};
+use pont_common::strategy::{GameView, Move};
 
 ////////////////////////////////////////////////////////////////////////////////
 
+// How long a bot waits before making its move, so that clients have time
+// to finish animating the previous turn.
+const BOT_DELAY: Duration = Duration::from_millis(1000);
+
+// If nobody has placed or swapped a piece in this many rounds, then the
+// game is over, even with pieces left in the bag (once the bag is empty,
+// one round is enough).  Running out of time counts as a pass, so this
+// keeps a round of absent players from ending a live game.
+const STALEMATE_ROUNDS: usize = 3;
+
+// A player whose clock has run out still gets this long for each turn,
+// so that a room full of flagged players doesn't spin through turns.
+const MIN_TURN_MS: u64 = 5000;
+
+// Returns the current time in milliseconds since the epoch,
+// which is used to timestamp events in the room's log
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Converts a hand (stored as piece counts) into a flat list of pieces
+fn hand_pieces(hand: &HashMap<Piece, usize>) -> Vec<Piece> {
+    let mut out = Vec::new();
+    for (piece, count) in hand.iter() {
+        for _i in 0..*count {
+            out.push(*piece);
+        }
+    }
+    out
+}
+
 lazy_static::lazy_static! {
     // words.txt is the EFF's random word list for passphrases
     static ref WORD_LIST: Vec<&'static str> = include_str!("words.txt")
         .split('\n')
         .filter(|w| !w.is_empty())
         .collect();
+
+    // Snapshots of open rooms are saved into this directory whenever they
+    // change, so that games survive a server restart.
+    static ref ROOM_DIR: PathBuf = PathBuf::from(env::var("PONT_ROOM_DIR")
+        .unwrap_or_else(|_| "rooms".to_string()));
+
+    // How long a disconnected player's hand is held for them (in seconds),
+    // before the seat is considered abandoned and the hand goes to the bag
+    static ref SEAT_GRACE: Duration = Duration::from_secs(
+        env::var("PONT_SEAT_GRACE").ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(300));
+}
+
+// Returns the path of the snapshot file for the given room
+fn snapshot_path(room_name: &str) -> PathBuf {
+    ROOM_DIR.join(format!("{}.room", room_name.replace(' ', "-")))
+}
+
+// Writes a room snapshot to disk.  It's written to a temporary file first,
+// so that a crash never leaves a partial file.  This blocks, so it should
+// be run with smol::blocking! rather than on the executor.
+fn write_snapshot(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
 }
 
 // Normally, a room exists as a relatively standalone task:
 // Client websockets send their messages to `write`, and `run_room` reads
-// them from `read` and applies them to the `room` object.
+// them from `read` and applies them to the `room` object.  The room can
+// also send delayed messages to itself (e.g. to run a bot's turn).
 //
 // It's made more complicated by the fact that adding players needs to
 // access the room object *before* clients are plugged into the `read`/`write`
 // infrastructure, so it must be shared and accessible from `handle_connection`
-type TaggedClientMessage = (SocketAddr, ClientMessage);
+enum RoomMessage {
+    Client(SocketAddr, ClientMessage),
+    BotTurn(usize),
+    SeatExpired(usize, usize),
+    TurnExpired(usize),
+
+    // Asks the room to save a snapshot after a change that was made
+    // outside of its queue (i.e. a player joining)
+    Save,
+}
+
 #[derive(Clone)]
 struct RoomHandle {
-    write: UnboundedSender<TaggedClientMessage>,
+    write: UnboundedSender<RoomMessage>,
     room: Arc<Mutex<Room>>,
 }
 
 impl RoomHandle {
     async fn run_room(&mut self,
-                      mut read: UnboundedReceiver<TaggedClientMessage>)
+                      mut read: UnboundedReceiver<RoomMessage>)
     {
-        while let Some((addr, msg)) = read.next().await {
-            if !self.room.lock().unwrap().on_message(addr, msg) {
+        while let Some(msg) = read.next().await {
+            let (running, snapshot) = {
+                let room = &mut self.room.lock().unwrap();
+                let running = match msg {
+                    RoomMessage::Client(addr, msg) =>
+                        room.on_message(addr, msg),
+                    RoomMessage::BotTurn(turn) => room.on_bot_turn(turn),
+                    RoomMessage::SeatExpired(p, n) =>
+                        room.on_seat_expired(p, n),
+                    RoomMessage::TurnExpired(t) => room.on_turn_expired(t),
+                    RoomMessage::Save => room.running(),
+                };
+                (running, room.take_snapshot())
+            };
+
+            // The room is unlocked while the snapshot is written, and only
+            // this task writes snapshots, so they land in order
+            if let Some((path, data)) = snapshot {
+                if let Err(e) = smol::blocking!(write_snapshot(&path, &data)) {
+                    let room = &mut self.room.lock().unwrap();
+                    error!("[{}] Failed to save snapshot: {}", room.name, e);
+                    room.dirty = true;
+                }
+            }
+            if !running {
+                // Dropping the spectators' queues closes their websockets
+                self.room.lock().unwrap().spectators.clear();
                 break;
             }
         }
     }
 }
 
-async fn run_player(player_name: String, addr: SocketAddr,
-                    handle: RoomHandle,
+async fn run_player(player_name: String, seat: Option<usize>,
+                    addr: SocketAddr, handle: RoomHandle,
                     ws_stream: WebSocketStream<Async<TcpStream>>)
 {
-    let (incoming, outgoing) = ws_stream.split();
-
     // Messages to the player's websocket are mediated by a queue,
     // with a separate async task reading messages from the queue
     // and pushing them down the websocket.  This lets us send messages to
@@ -73,12 +177,41 @@ async fn run_player(player_name: String, addr: SocketAddr,
 
     {   // Briefly lock the room to add the player
         let room = &mut handle.room.lock().unwrap();
-        if let Err(e) = room.add_player(addr, player_name.clone(), ws_tx) {
+        if let Err(e) = room.add_player(addr, player_name.clone(), seat, ws_tx) {
             error!("[{}] Failed to add player: {:?}",
                    room.name, e);
             return;
         }
     }
+    if let Err(e) = handle.write.unbounded_send(RoomMessage::Save) {
+        error!("[{}] Failed to ask for a snapshot: {}", addr, e);
+    }
+    run_session(player_name, addr, handle, ws_rx, ws_stream).await;
+}
+
+// Spectators get the same broadcasts as players, but don't have a seat
+async fn run_spectator(addr: SocketAddr, handle: RoomHandle,
+                       ws_stream: WebSocketStream<Async<TcpStream>>)
+{
+    let (ws_tx, ws_rx) = unbounded();
+    {
+        let room = &mut handle.room.lock().unwrap();
+        if let Err(e) = room.add_spectator(addr, ws_tx) {
+            error!("[{}] Failed to add spectator: {:?}", room.name, e);
+            return;
+        }
+    }
+    run_session("spectator".to_string(), addr, handle, ws_rx, ws_stream)
+        .await;
+}
+
+// Passes messages between a websocket and its room, until either side
+// hangs up.  The room is told about the disconnection at the end.
+async fn run_session(name: String, addr: SocketAddr, handle: RoomHandle,
+                     ws_rx: UnboundedReceiver<ServerMessage>,
+                     ws_stream: WebSocketStream<Async<TcpStream>>)
+{
+    let (incoming, outgoing) = ws_stream.split();
 
     let write = handle.write.clone();
     let ra = ws_rx
@@ -97,39 +230,110 @@ async fn run_player(player_name: String, addr: SocketAddr,
         .map(|m| m.unwrap())
         .chain(futures::stream::once(async {
             ClientMessage::Disconnected }))
-        .map(move |m| Ok((addr, m)))
+        .map(move |m| Ok(RoomMessage::Client(addr, m)))
         .forward(write);
     let (ra, rb) = join(ra, rb).await;
 
     if let Err(e) = ra {
         error!("[{}] Got error {} from player {}'s rx queue",
-               addr, e, player_name);
+               addr, e, name);
     }
     if let Err(e) = rb {
         error!("[{}] Got error {} from player {}'s tx queue",
-               addr, e, player_name);
+               addr, e, name);
     }
-    info!("[{}] Finished session with {}", addr, player_name);
+    info!("[{}] Finished session with {}", addr, name);
 }
 
 type RoomList = Arc<Mutex<HashMap<String, RoomHandle>>>;
 
-#[derive(Default)]
+// Rooms are serialized (minus their live connections) into snapshot files
+#[derive(Default, Deserialize, Serialize)]
 struct Room {
     name: String,
     started: bool,
     ended: bool,
+    #[serde(skip)]
     connections: HashMap<SocketAddr, usize>,
+
+    // Connections that are watching the game without a seat
+    #[serde(skip)]
+    spectators: HashMap<SocketAddr, UnboundedSender<ServerMessage>>,
     players: Vec<Player>,
     active_player: usize,
     game: Game,
+
+    // Incremented every time the active player changes, so that delayed
+    // messages can tell whether they're still relevant.
+    turn: usize,
+
+    // Number of turns in a row where nobody played or swapped pieces,
+    // which is used to detect a stalemate
+    passes: usize,
+
+    // Used to send delayed messages back into the room's own queue
+    #[serde(skip)]
+    write: Option<UnboundedSender<RoomMessage>>,
+
+    // Append-only record of everything that has happened in the room,
+    // which is saved as a replay when the room closes.
+    log: Replay,
+
+    // The host is the player who created the room, and decides whether
+    // disconnected seats can be taken by name (rather than by token)
+    host: usize,
+    name_rejoin: bool,
+
+    // Seats in the order that they take turns, which the host can change
+    // while the room is in the lobby
+    order: Vec<usize>,
+
+    // When the active player's turn started, and how long it can last (in
+    // milliseconds), if the rules have a time limit.  The turn timer isn't
+    // saved in snapshots; it restarts when someone rejoins the room.
+    #[serde(skip)]
+    turn_start: Option<Instant>,
+    #[serde(skip)]
+    turn_limit: u64,
+
+    // Incremented every time the turn timer starts, so that a delayed
+    // TurnExpired message can tell whether it's still relevant.
+    #[serde(skip)]
+    timer: usize,
+
+    // Set when something that belongs in a snapshot changes, so that
+    // messages like chat and stale timers don't cause a save
+    #[serde(skip)]
+    dirty: bool,
 }
 
+#[derive(Deserialize, Serialize)]
 struct Player {
     name: String,
     score: u32,
+    breakdown: ScoreBreakdown,
     hand: HashMap<Piece, usize>,
-    ws: Option<UnboundedSender<ServerMessage>>
+    #[serde(skip)]
+    ws: Option<UnboundedSender<ServerMessage>>,
+    bot: Option<Difficulty>,
+
+    // Secret that lets a disconnected player reclaim this seat
+    token: String,
+
+    // Number of times this player has disconnected, so that a delayed
+    // SeatExpired message can tell whether they've come back since.
+    disconnects: usize,
+
+    // Milliseconds left on this player's total clock, if the rules have one
+    clock_ms: u64,
+
+    // Whether the player is ready for the game to start
+    ready: bool,
+
+    // Set by the host.  Muted players can't chat, and kicked players
+    // can't take their seat back.
+    muted: bool,
+    kicked: bool,
 }
 
 impl Player {
@@ -142,7 +346,7 @@ impl Player {
         }
 
         for (piece, n) in count.iter() {
-            if let Some(m) = self.hand.get(&piece) {
+            if let Some(m) = self.hand.get(piece) {
                 if *m < *n {
                     return false;
                 }
@@ -152,13 +356,20 @@ impl Player {
         }
 
         for (piece, n) in count.iter() {
-            if let Some(m) = self.hand.get_mut(&piece) {
+            if let Some(m) = self.hand.get_mut(piece) {
                 *m -= n;
             }
         }
         true
     }
 
+    // Puts pieces back into the player's hand, undoing try_remove
+    fn give_back(&mut self, pieces: &[Piece]) {
+        for piece in pieces {
+            *self.hand.entry(*piece).or_insert(0) += 1;
+        }
+    }
+
     fn hand_is_empty(&self) -> bool {
         self.hand.values().all(|i| *i == 0)
     }
@@ -166,11 +377,102 @@ impl Player {
     fn hand_size(&self) -> usize {
         self.hand.values().sum::<usize>()
     }
+
+    // Bots don't have a websocket, but are always able to take their turn
+    fn is_active(&self) -> bool {
+        self.ws.is_some() || self.bot.is_some()
+    }
+
+    fn can_rejoin(&self) -> bool {
+        !self.is_active() && !self.kicked
+    }
 }
 
 impl Room {
+    // The room keeps running as long as someone is connected, or while
+    // a disconnected player's hand is still being held for them.
     fn running(&self) -> bool {
-        !self.connections.is_empty()
+        !self.connections.is_empty() ||
+            self.players.iter().any(|p| !p.is_active() && !p.hand_is_empty())
+    }
+
+    // Records an event in the replay.  Every change to the game goes
+    // through here, so it also marks the room as needing a snapshot (except
+    // for chat, which isn't worth a save of its own).
+    fn log(&mut self, event: Event) {
+        if !matches!(event, Event::Chat { .. }) {
+            self.dirty = true;
+        }
+        self.log.push(now_ms(), event);
+    }
+
+    // Serializes a snapshot if anything has changed since the last one,
+    // returning it along with the path that it should be written to.  This
+    // happens under the room's lock, but writing the file doesn't have to
+    // (see write_snapshot).  If the write fails, the room is marked as
+    // dirty again, so that the next message tries again.
+    fn take_snapshot(&mut self) -> Option<(PathBuf, Vec<u8>)> {
+        if !self.dirty {
+            return None;
+        }
+        match bincode::serialize(self) {
+            Ok(data) => {
+                self.dirty = false;
+                Some((snapshot_path(&self.name), data))
+            },
+            Err(e) => {
+                error!("[{}] Failed to serialize snapshot: {}", self.name, e);
+                None
+            },
+        }
+    }
+
+    // Loads a room from a snapshot file
+    fn load(path: &Path) -> Result<Room> {
+        Ok(bincode::deserialize(&fs::read(path)?)?)
+    }
+
+    // Nobody is connected to a restored room, so every human player is
+    // treated as if they had just disconnected.
+    fn on_restored(&mut self) {
+        for i in 0..self.players.len() {
+            if self.players[i].bot.is_none() {
+                self.on_seat_left(i);
+            }
+        }
+    }
+
+    // Marks a seat as disconnected, holding the player's hand for them
+    // until the grace period runs out.
+    fn on_seat_left(&mut self, p: usize) {
+        let player = &mut self.players[p];
+        player.ws = None;
+        player.disconnects += 1;
+        let n = player.disconnects;
+        self.log(Event::Disconnected(p));
+        self.schedule(*SEAT_GRACE, RoomMessage::SeatExpired(p, n));
+    }
+
+    fn on_seat_expired(&mut self, p: usize, n: usize) -> bool {
+        let player = &self.players[p];
+        if !player.is_active() && player.disconnects == n &&
+           !player.hand_is_empty()
+        {
+            info!("[{}] Returning abandoned hand of '{}' to the bag",
+                  self.name, player.name);
+            self.return_hand(p);
+            self.broadcast(ServerMessage::PiecesRemaining(self.game.bag.len()));
+        }
+        self.running()
+    }
+
+    // Puts a player's hand back into the bag
+    fn return_hand(&mut self, p: usize) {
+        let pieces = hand_pieces(&self.players[p].hand);
+        self.players[p].hand.clear();
+        self.game.bag.extend(pieces.iter());
+        self.game.shuffle();
+        self.log(Event::Returned { player: p, pieces });
     }
 
     fn broadcast(&self, s: ServerMessage) {
@@ -182,6 +484,16 @@ impl Room {
                 }
             }
         }
+        self.send_spectators(&s);
+    }
+
+    fn send_spectators(&self, s: &ServerMessage) {
+        for (addr, ws) in self.spectators.iter() {
+            if let Err(e) = ws.unbounded_send(s.clone()) {
+                error!("[{}] Failed to send message to spectator {}: {}",
+                       self.name, addr, e);
+            }
+        }
     }
 
     fn broadcast_except(&self, i: usize, s: ServerMessage) {
@@ -195,10 +507,13 @@ impl Room {
                 }
             }
         }
+        self.send_spectators(&s);
     }
 
     fn send(&self, i: usize, s: ServerMessage) {
-        if let Some(p) = self.players[i].ws.as_ref() {
+        if self.players[i].bot.is_some() {
+            // Bots don't need to be told anything
+        } else if let Some(p) = self.players[i].ws.as_ref() {
             if let Err(e) = p.unbounded_send(s) {
                 error!("[{}] Failed to send message to {}: {}",
                        self.name, self.players[i].name, e);
@@ -208,202 +523,768 @@ impl Room {
         }
     }
 
+    // Returns the index of the disconnected seat with the given token
+    fn find_seat(&self, token: &str) -> Option<usize> {
+        self.players.iter().position(|p|
+            !p.token.is_empty() && p.token == token && p.can_rejoin())
+    }
+
+    // New seats are limited by the room's seat count, and are only
+    // available in the lobby unless the host leaves the room unlocked.
+    // A player can always come back to a seat that they left.
+    fn join_error(&self, player_name: &str) -> Option<String> {
+        let reclaim = self.name_rejoin && self.players.iter()
+            .any(|p| p.name == player_name && p.can_rejoin());
+        if reclaim {
+            None
+        } else if self.is_locked() {
+            Some(format!("The game in '{}' has already started", self.name))
+        } else if self.is_full() {
+            Some(format!("Room '{}' is full ({} seats)",
+                         self.name, self.game.rules.max_seats))
+        } else {
+            None
+        }
+    }
+
+    // Whether the game has started and no longer takes new seats
+    fn is_locked(&self) -> bool {
+        self.started && (self.game.rules.lock_on_start || self.ended)
+    }
+
+    // Kicked players don't count against the seat limit
+    fn is_full(&self) -> bool {
+        self.players.iter().filter(|p| !p.kicked).count() >=
+            self.game.rules.max_seats
+    }
+
+    // Adds a player to the room.  If seat is given, then the player takes
+    // over that (disconnected) seat; otherwise, they get a new seat, unless
+    // the host allows seats to be reclaimed by name.
     fn add_player(&mut self, addr: SocketAddr, player_name: String,
+                  seat: Option<usize>,
                   ws_tx: UnboundedSender<ServerMessage>) -> Result<()>
     {
-        // Pick out a hand for our new player
-        let hand = self.game.deal(6);
-        let mut pieces = Vec::new();
-        for (piece, count) in hand.iter() {
-            for _i in 0..*count {
-                pieces.push(piece.clone());
+        if let Some(i) = seat {
+            if i >= self.players.len() || !self.players[i].can_rejoin() {
+                anyhow::bail!("Seat {} is not available", i);
             }
         }
 
         // Check whether the new player's name matches an old name of someone
         // that has disconnected.  If so, we can take their seat.
-        let mut player_index = None;
-        for (i, p) in self.players.iter().enumerate() {
-            if p.name == player_name && p.ws.is_none() {
-                player_index = Some(i);
-                break;
+        let mut player_index = seat;
+        if player_index.is_none() && self.name_rejoin {
+            for (i, p) in self.players.iter().enumerate() {
+                if p.name == player_name && p.can_rejoin() {
+                    player_index = Some(i);
+                    break;
+                }
             }
         }
 
-        if let Some(i) = player_index {
-            // Reclaim the player's spot
+        let dealt = if let Some(i) = player_index {
+            // Reclaim the player's spot.  If their hand was held for them,
+            // then they get it back; otherwise, they're dealt a new one
+            // (unless the game hasn't started yet)
             self.broadcast(ServerMessage::PlayerReconnected(i));
-            self.players[i].hand = hand;
-            self.players[i].ws = Some(ws_tx.clone());
+            self.log(Event::Rejoined(i));
+            let player = &mut self.players[i];
+            let hand = if self.started {
+                self.game.deal(self.game.rules.hand_size - player.hand_size())
+            } else {
+                HashMap::new()
+            };
+            for (piece, count) in hand.iter() {
+                *player.hand.entry(*piece).or_insert(0) += count;
+            }
+            player.ws = Some(ws_tx.clone());
+            hand_pieces(&hand)
+        } else if self.is_locked() {
+            anyhow::bail!("Game has already started");
+        } else if self.is_full() {
+            anyhow::bail!("Room is full");
         } else {
             self.broadcast(ServerMessage::NewPlayer(player_name.clone()));
+            self.log(Event::Joined(player_name.clone()));
             player_index = Some(self.players.len());
+            self.order.push(self.players.len());
+
+            // Players who join in the lobby are dealt in when the game
+            // starts; anyone joining an unlocked game gets a hand right away.
+            let hand = if self.started {
+                self.game.deal(self.game.rules.hand_size)
+            } else {
+                HashMap::new()
+            };
+            let dealt = hand_pieces(&hand);
 
             self.players.push(Player {
                 name: player_name,
                 score: 0,
+                breakdown: ScoreBreakdown::default(),
                 hand,
-                ws: Some(ws_tx.clone()) });
-        }
+                ws: Some(ws_tx.clone()),
+                bot: None,
+                token: format!("{:032x}", rand::thread_rng().gen::<u128>()),
+                disconnects: 0,
+                clock_ms: self.game.rules.clock_secs as u64 * 1000,
+                ready: false,
+                muted: false,
+                kicked: false,
+            });
+            dealt
+        };
 
         // At this point, the option must be assigned, so we unwrap it
         let player_index = player_index.unwrap();
+        if self.started {
+            self.log(Event::Dealt { player: player_index, pieces: dealt });
+        }
+        let pieces = hand_pieces(&self.players[player_index].hand);
 
         // Add the new player to the active list of connections and players
         self.connections.insert(addr, player_index);
 
-        // The game counts as started once the first player joins
-        self.started = true;
-
         // Tell the player that they have joined the room
         ws_tx.unbounded_send(ServerMessage::JoinedRoom{
                 room_name: self.name.clone(),
-                players: self.players.iter()
-                    .map(|p| (p.name.clone(), p.score, p.ws.is_some()))
-                    .collect(),
+                players: self.player_list(),
                 active_player: self.active_player,
                 player_index,
-                board: self.game.board.iter()
-                    .map(|(k, v)| (*k, *v))
-                    .collect(),
+                board: self.board_list(),
                 pieces,
+                rules: self.game.rules,
+                started: self.started,
+                token: self.players[player_index].token.clone(),
             })?;
+        self.catch_up(&ws_tx)?;
 
         // Because we've removed pieces from the bag, update the
         // pieces remaining that clients know about.
         self.broadcast(ServerMessage::PiecesRemaining(self.game.bag.len()));
+
+        // If this is the first player back in a restored room, then it may
+        // be nobody's turn (or a bot's turn that was never scheduled), so
+        // we get the game moving again.
+        if self.connections.len() == 1 && self.started && !self.ended {
+            let active = &self.players[self.active_player];
+            if !active.is_active() {
+                self.next_player();
+            } else {
+                if active.bot.is_some() {
+                    self.schedule_bot_turn();
+                }
+                self.start_turn();
+            }
+        }
         Ok(())
     }
 
-    fn next_player(&mut self) {
-        if !self.connections.is_empty() {
-            self.active_player = (self.active_player + 1) %
-                                  self.players.len();
-            while self.players[self.active_player].ws.is_none() {
-                self.active_player = (self.active_player + 1) %
-                                      self.players.len();
+    fn add_spectator(&mut self, addr: SocketAddr,
+                     ws_tx: UnboundedSender<ServerMessage>) -> Result<()>
+    {
+        info!("[{}] Adding spectator at {}", self.name, addr);
+        ws_tx.unbounded_send(ServerMessage::Spectating {
+                room_name: self.name.clone(),
+                players: self.player_list(),
+                active_player: self.active_player,
+                board: self.board_list(),
+                rules: self.game.rules,
+                started: self.started,
+            })?;
+        self.catch_up(&ws_tx)?;
+        ws_tx.unbounded_send(
+            ServerMessage::PiecesRemaining(self.game.bag.len()))?;
+        self.spectators.insert(addr, ws_tx);
+        Ok(())
+    }
+
+    fn player_list(&self) -> Vec<(String, u32, bool)> {
+        self.players.iter()
+            .map(|p| (p.name.clone(), p.score, p.is_active()))
+            .collect()
+    }
+
+    fn board_list(&self) -> Vec<((i32, i32), Piece)> {
+        self.game.board.iter()
+            .map(|(k, v)| (*k, *v))
+            .collect()
+    }
+
+    // Tells someone who just arrived about everything that isn't in
+    // JoinedRoom: the host, the lobby, the clocks, and the final standings
+    fn catch_up(&self, ws_tx: &UnboundedSender<ServerMessage>) -> Result<()> {
+        ws_tx.unbounded_send(ServerMessage::NewHost(self.host))?;
+        for (i, p) in self.players.iter().enumerate() {
+            if p.kicked {
+                ws_tx.unbounded_send(ServerMessage::PlayerKicked(i))?;
+            } else if p.muted {
+                ws_tx.unbounded_send(ServerMessage::PlayerMuted(i, true))?;
             }
-            debug!("[{}] Active player changed to {}", self.name,
-                   self.players[self.active_player].name);
+        }
 
-            self.broadcast(ServerMessage::PlayerTurn(self.active_player));
+        if !self.started {
+            ws_tx.unbounded_send(ServerMessage::TurnOrder(self.order.clone()))?;
+            for (i, p) in self.players.iter().enumerate() {
+                if p.ready {
+                    ws_tx.unbounded_send(ServerMessage::PlayerReady(i, true))?;
+                }
+            }
         }
+
+        if self.game.rules.clock_secs > 0 {
+            for (i, p) in self.players.iter().enumerate() {
+                ws_tx.unbounded_send(ServerMessage::ClockTime(i, p.clock_ms))?;
+            }
+        }
+        if let Some(t) = self.turn_start {
+            let elapsed = t.elapsed().as_millis() as u64;
+            ws_tx.unbounded_send(ServerMessage::TurnTime(
+                self.turn_limit.saturating_sub(elapsed)))?;
+        }
+
+        if self.ended {
+            ws_tx.unbounded_send(ServerMessage::ItsOver(self.standings()))?;
+        }
+        Ok(())
     }
 
-    fn on_client_disconnected(&mut self, addr: SocketAddr) {
-        if let Some(p) = self.connections.remove(&addr) {
-            let player_name = self.players[p].name.clone();
-            info!("[{}] Removed disconnected player '{}'",
-                  self.name, player_name);
-            self.players[p].ws = None;
-            for (k, v) in self.players[p].hand.drain() {
-                for _i in 0..v {
-                    self.game.bag.push(k.clone());
+    fn add_bot(&mut self, difficulty: Difficulty) {
+        let n = self.players.iter().filter(|p| p.bot.is_some()).count();
+        let name = format!("Bot {} ({:?})", n + 1, difficulty);
+        info!("[{}] Adding bot '{}'", self.name, name);
+
+        // Bots are always ready, and are dealt in when the game starts
+        let i = self.players.len();
+        self.broadcast(ServerMessage::NewPlayer(name.clone()));
+        self.broadcast(ServerMessage::PlayerReady(i, true));
+        self.log(Event::Joined(name.clone()));
+        self.players.push(Player {
+            name,
+            score: 0,
+            breakdown: ScoreBreakdown::default(),
+            hand: HashMap::new(),
+            ws: None,
+            bot: Some(difficulty),
+            token: String::new(),
+            disconnects: 0,
+            clock_ms: self.game.rules.clock_secs as u64 * 1000,
+            ready: true,
+            muted: false,
+            kicked: false,
+        });
+        self.order.push(i);
+    }
+
+    fn is_host(&self, addr: SocketAddr) -> bool {
+        self.connections.get(&addr) == Some(&self.host)
+    }
+
+    // Replaces the rules while in the lobby.  The game is rebuilt with the
+    // same seed, so the replay log only needs to know the final rules.
+    fn set_rules(&mut self, rules: RuleSet) {
+        self.game = Game::with_rules(rules, self.game.seed);
+        self.log.rules = rules;
+        self.dirty = true;
+        self.broadcast(ServerMessage::RulesChanged(rules));
+
+        // Everyone has to agree to the new rules before starting
+        for i in 0..self.players.len() {
+            let player = &mut self.players[i];
+            player.clock_ms = rules.clock_secs as u64 * 1000;
+            if player.bot.is_none() && player.ready {
+                player.ready = false;
+                self.broadcast(ServerMessage::PlayerReady(i, false));
+            }
+        }
+    }
+
+    // Checks that the new turn order includes every seat exactly once
+    fn set_turn_order(&mut self, order: Vec<usize>) -> bool {
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        if sorted != (0..self.players.len()).collect::<Vec<_>>() {
+            return false;
+        }
+        self.order = order;
+        self.dirty = true;
+        self.broadcast(ServerMessage::TurnOrder(self.order.clone()));
+        true
+    }
+
+    // Deals a hand to everyone who is here, then starts the first turn.
+    // Disconnected players are dealt in if they come back.
+    fn start_game(&mut self) {
+        info!("[{}] Starting game with {} players", self.name,
+              self.players.len());
+        self.started = true;
+        for i in self.order.clone() {
+            if !self.players[i].is_active() {
+                continue;
+            }
+            let hand = self.game.deal(self.game.rules.hand_size);
+            let pieces = hand_pieces(&hand);
+            self.players[i].hand = hand;
+            self.log(Event::Dealt { player: i, pieces: pieces.clone() });
+            if self.players[i].ws.is_some() {
+                self.send(i, ServerMessage::GameStarted(pieces));
+            }
+        }
+        self.broadcast(ServerMessage::PiecesRemaining(self.game.bag.len()));
+
+        // Whoever holds the longest line goes first, with ties broken by
+        // the turn order
+        let mut first: Option<(usize, usize)> = None;
+        for i in self.order.iter().copied() {
+            if self.players[i].is_active() {
+                let n = self.opening_size(i);
+                if !matches!(first, Some((m, _)) if m >= n) {
+                    first = Some((n, i));
                 }
             }
-            self.game.shuffle();
-            self.broadcast(ServerMessage::PlayerDisconnected(p));
+        }
+        if let Some((n, i)) = first {
+            self.active_player = i;
+            self.broadcast(ServerMessage::Information(format!(
+                "{} has the longest line ({} pieces), so they go first",
+                self.players[i].name, n)));
+        }
+        self.turn += 1;
+        self.log(Event::Turn(self.active_player));
+        self.broadcast(ServerMessage::PlayerTurn(self.active_player));
+        if self.players[self.active_player].bot.is_some() {
+            self.schedule_bot_turn();
+        }
+        self.start_turn();
+    }
 
-            // We've put pieces back in the bag, so update the piece count
-            self.broadcast(ServerMessage::PiecesRemaining(self.game.bag.len()));
+    // Returns the length of the longest line in a player's hand
+    fn opening_size(&self, p: usize) -> usize {
+        Game::opening_line(&hand_pieces(&self.players[p].hand)).len()
+    }
 
-            // Find the next active player and broadcast out that info
-            if p == self.active_player {
-                self.next_player();
+    // Under the strict opening rule, the first play must be the longest
+    // line that the player can make
+    fn must_open(&self) -> bool {
+        self.game.rules.strict_opening && self.game.board.is_empty()
+    }
+
+    // Returns the next active seat after p in the turn order
+    fn next_seat(&self, p: usize) -> usize {
+        let n = self.order.len();
+        let mut pos = self.order.iter().position(|i| *i == p).unwrap_or(0);
+        loop {
+            pos = (pos + 1) % n;
+            if self.players[self.order[pos]].is_active() {
+                return self.order[pos];
             }
-        } else {
-            error!("[{}] Tried to remove non-existent player at {}",
-                     self.name, addr);
         }
     }
 
-    fn on_play(&mut self, pieces: &[(Piece, i32, i32)]) {
-        let player = &mut self.players[self.active_player];
+    fn next_player(&mut self) {
+        if self.started && !self.connections.is_empty() {
+            self.end_turn();
+            if self.is_stalemate() {
+                let msg = "Nobody can make progress, so the game is over";
+                self.broadcast(ServerMessage::Information(msg.to_string()));
+                self.end_game();
+                return;
+            }
+            self.active_player = self.next_seat(self.active_player);
+            self.turn += 1;
+            self.log(Event::Turn(self.active_player));
+            debug!("[{}] Active player changed to {}", self.name,
+                   self.players[self.active_player].name);
+
+            self.broadcast(ServerMessage::PlayerTurn(self.active_player));
 
-        let mut board = self.game.board.clone();
-        for (piece, x, y) in pieces.iter() {
-            board.insert((*x, *y), *piece);
+            if self.players[self.active_player].bot.is_some() {
+                self.schedule_bot_turn();
+            }
+            self.start_turn();
         }
-        let played = pieces.iter().map(|p| (p.1, p.2)).collect::<Vec<_>>();
-        if !Game::invalid(&board).is_empty() ||
-           !Game::is_linear_connected(&board, &played)
-        {
-            warn!("[{}] Player {} tried to make an illegal move",
-                  self.name, player.name);
-            self.send(self.active_player, ServerMessage::MoveRejected);
+    }
+
+    fn on_pass(&mut self) {
+        self.passes += 1;
+        self.log(Event::Passed(self.active_player));
+    }
+
+    // The game is stuck if every remaining player has passed for enough
+    // rounds in a row, or if the bag is empty and none of them have a
+    // legal play
+    fn is_stalemate(&self) -> bool {
+        let active = self.players.iter()
+            .filter(|p| p.is_active())
+            .collect::<Vec<_>>();
+        let rounds = if self.game.bag.is_empty() {
+            1
+        } else {
+            STALEMATE_ROUNDS
+        };
+        if self.passes >= rounds * active.len() {
+            return true;
+        }
+        self.game.bag.is_empty() && active.iter().all(|p| {
+            let hand = hand_pieces(&p.hand);
+            Game::legal_moves(&self.game.board, &hand).is_empty()
+        })
+    }
+
+    fn standings(&self) -> Vec<Standing> {
+        let mut standings = self.players.iter()
+            .enumerate()
+            .map(|(i, p)| Standing {
+                player: i,
+                place: 0,
+                score: p.score,
+                breakdown: p.breakdown,
+                tiles_left: p.hand_size(),
+            })
+            .collect::<Vec<_>>();
+        Standing::rank(&mut standings);
+        standings
+    }
+
+    fn end_game(&mut self) {
+        self.broadcast(ServerMessage::ItsOver(self.standings()));
+        self.log(Event::Ended);
+        self.ended = true;
+        self.turn_start = None;
+    }
+
+    // Starts the active player's turn timer, if the rules have one
+    fn start_turn(&mut self) {
+        let rules = self.game.rules;
+        if !rules.is_timed() || self.ended {
             return;
         }
+        let mut limit = u64::MAX;
+        if rules.turn_secs > 0 {
+            limit = rules.turn_secs as u64 * 1000;
+        }
+        if rules.clock_secs > 0 {
+            let clock = self.players[self.active_player].clock_ms;
+            limit = limit.min(clock.max(MIN_TURN_MS));
+        }
+        self.turn_start = Some(Instant::now());
+        self.turn_limit = limit;
+        self.timer += 1;
+        self.broadcast(ServerMessage::TurnTime(limit));
+        self.schedule(Duration::from_millis(limit),
+                      RoomMessage::TurnExpired(self.timer));
+    }
 
-        {   // Remove the pieces from the player's hand
-            let pieces: Vec<Piece> = pieces.iter().map(|p| p.0).collect();
-            if !player.try_remove(&pieces) {
-                warn!("[{}] Player {} tried to play an unowned piece",
-                      self.name, player.name);
-                self.send(self.active_player, ServerMessage::MoveRejected);
-                return;
+    // Stops the turn timer, charging the time used to the active player's
+    // clock (then adding the per-turn increment)
+    fn end_turn(&mut self) {
+        let rules = self.game.rules;
+        if let Some(t) = self.turn_start.take() {
+            if rules.clock_secs > 0 {
+                let elapsed = t.elapsed().as_millis() as u64;
+                let p = self.active_player;
+                let player = &mut self.players[p];
+                player.clock_ms = player.clock_ms.saturating_sub(elapsed) +
+                                  rules.increment_secs as u64 * 1000;
+                let clock = player.clock_ms;
+                self.broadcast(ServerMessage::ClockTime(p, clock));
             }
         }
+    }
+
+    // When a player runs out of time, they pass
+    fn on_turn_expired(&mut self, timer: usize) -> bool {
+        // As with bots, the clock stops while nobody is connected
+        if timer != self.timer || self.turn_start.is_none() || self.ended ||
+           self.connections.is_empty()
+        {
+            return self.running();
+        }
+        let p = self.active_player;
+        info!("[{}] Player {} ran out of time", self.name,
+              self.players[p].name);
+        self.broadcast_except(p, ServerMessage::Information(
+            format!("{} ran out of time", self.players[p].name)));
+
+        // Cancel any move that the player's client is in the middle of,
+        // which also tells them why
+        self.send(p, ServerMessage::MoveRejected(MoveError::OutOfTime));
+
+        // Running out the clock on the opening play doesn't count as a pass
+        // (which could end the game before it starts); the next player has
+        // to open instead.
+        if self.must_open() {
+            self.log(Event::Passed(p));
+        } else {
+            self.on_pass();
+        }
+        self.next_player();
+        self.running()
+    }
 
-        if let Some(mut delta) = self.game.play(pieces) {
-            // Broadcast the new score to all players
-            let mut deal = Vec::new();
-            for (piece, count) in self.game.deal(6 - player.hand_size()) {
-                *player.hand.entry(piece).or_insert(0) += count;
-                for _i in 0..count {
-                    deal.push(piece);
+    // Sends a message back into the room's own queue after a delay
+    fn schedule(&self, delay: Duration, msg: RoomMessage) {
+        if let Some(write) = self.write.clone() {
+            Task::spawn(async move {
+                Timer::after(delay).await;
+                if let Err(e) = write.unbounded_send(msg) {
+                    error!("Failed to send delayed message: {}", e);
                 }
+            }).detach();
+        }
+    }
+
+    // Asks the room to run the active bot's turn after a short delay
+    fn schedule_bot_turn(&self) {
+        self.schedule(BOT_DELAY, RoomMessage::BotTurn(self.turn));
+    }
+
+    fn on_bot_turn(&mut self, turn: usize) -> bool {
+        // Ignore stale messages, e.g. if the game ended while we were waiting.
+        // If nobody is connected, then the bot waits for a player to return.
+        if turn != self.turn || self.ended || self.connections.is_empty() {
+            return self.running();
+        }
+        let player = &self.players[self.active_player];
+        let difficulty = match player.bot {
+            Some(d) => d,
+            None => {
+                error!("[{}] Got bot turn for human player {}",
+                       self.name, player.name);
+                return self.running();
             }
-            // Check whether the game is over!
-            let over = player.hand_is_empty() && self.game.bag.is_empty();
-            if over {
-                delta += 6;
+        };
+
+        let hand = hand_pieces(&player.hand);
+        let scores = self.players.iter().map(|p| p.score).collect::<Vec<_>>();
+        let view = GameView {
+            rules: self.game.rules,
+            board: &self.game.board,
+            hand: &hand,
+            bag_size: self.game.bag.len(),
+            scores: &scores,
+            player_index: self.active_player,
+        };
+        // Strategies don't know about the opening rule, so bots make the
+        // required play directly
+        let m = if self.must_open() {
+            Move::Play(Game::opening_line(&hand))
+        } else {
+            difficulty.strategy().choose(&view)
+        };
+        let result = match m {
+            Move::Play(pieces) => self.on_play(&pieces),
+            Move::Swap(pieces) => self.on_swap(&pieces),
+            Move::Pass => {
+                let msg = format!("{} can't move, so they pass", player.name);
+                self.broadcast(ServerMessage::Information(msg));
+                self.on_pass();
+                Ok(())
+            },
+        };
+        // A bot that makes an illegal move passes, rather than getting
+        // stuck trying the same move forever
+        if let Err(e) = result {
+            error!("[{}] Bot {} made an illegal move: {}", self.name,
+                   self.players[self.active_player].name, e);
+            self.on_pass();
+        }
+        if !self.ended {
+            self.next_player();
+        }
+        self.running()
+    }
+
+    fn on_client_disconnected(&mut self, addr: SocketAddr) {
+        if self.spectators.remove(&addr).is_some() {
+            info!("[{}] Removed spectator at {}", self.name, addr);
+        } else if let Some(p) = self.connections.remove(&addr) {
+            let player_name = self.players[p].name.clone();
+            info!("[{}] Removed disconnected player '{}'",
+                  self.name, player_name);
+            self.leave_seat(p);
+        } else {
+            // This happens when a player has been kicked
+            debug!("[{}] Connection {} had already left", self.name, addr);
+        }
+    }
+
+    // Empties a seat whose player has disconnected or been kicked
+    fn leave_seat(&mut self, p: usize) {
+        self.on_seat_left(p);
+        self.broadcast(ServerMessage::PlayerDisconnected(p));
+
+        // Someone has to be able to start the game, so the host role
+        // passes to the next person who is still here
+        if p == self.host {
+            if let Some(h) = self.players.iter()
+                .position(|q| q.ws.is_some())
+            {
+                self.host = h;
+                self.broadcast(ServerMessage::NewHost(h));
             }
-            player.score += delta;
+        }
+
+        // Find the next active player and broadcast out that info
+        if p == self.active_player {
+            self.next_player();
+        }
+    }
 
-            let total = player.score; // Release the borrow of player
-            self.broadcast(ServerMessage::PlayerScore { delta, total });
+    // Removes a player from the room for good, putting their hand back
+    // into the bag right away (rather than holding it for them)
+    fn kick(&mut self, p: usize) {
+        info!("[{}] Kicking player '{}'", self.name, self.players[p].name);
+        self.broadcast(ServerMessage::PlayerKicked(p));
+        let player = &mut self.players[p];
+        player.kicked = true;
+        player.bot = None;
+
+        // Emptying the seat drops the player's queue, which closes their
+        // websocket; the Disconnected message that follows is ignored.
+        let addr = self.connections.iter()
+            .find(|(_, i)| **i == p)
+            .map(|(a, _)| *a);
+        if let Some(addr) = addr {
+            self.connections.remove(&addr);
+        }
+
+        if !self.players[p].hand_is_empty() {
+            self.return_hand(p);
             self.broadcast(ServerMessage::PiecesRemaining(self.game.bag.len()));
-            self.send(self.active_player, ServerMessage::MoveAccepted(deal));
+        }
+        self.leave_seat(p);
+    }
 
-            // Broadcast the play to other players
-            self.broadcast_except(self.active_player,
-                ServerMessage::Played(pieces.to_vec()));
+    // Checks that the host is allowed to moderate the given seat, telling
+    // them why not otherwise
+    fn can_moderate(&self, addr: SocketAddr, p: usize) -> bool {
+        if !self.is_host(addr) {
+            warn!("[{}] Non-host {} tried to moderate", self.name, addr);
+            return false;
+        }
+        let err = if p >= self.players.len() {
+            "There is no such player"
+        } else if p == self.host {
+            "You can't do that to yourself"
+        } else if self.players[p].kicked {
+            "That player has already been removed"
+        } else {
+            return true;
+        };
+        self.send(self.host, ServerMessage::Information(err.to_string()));
+        false
+    }
 
-            if over {
-                let winner = self.players.iter()
-                    .enumerate()
-                    .max_by_key(|(_i, p)| p.score).unwrap().0;
-                self.broadcast(ServerMessage::ItsOver(winner));
-                self.ended = true;
+    // Turns down a move, telling the player why
+    fn reject(&self, p: usize, e: MoveError) {
+        warn!("[{}] Rejected move from {}: {}",
+              self.name, self.players[p].name, e);
+        self.send(p, ServerMessage::MoveRejected(e));
+    }
+
+    // Applies a play by the active player.  A rejected play leaves the room
+    // untouched, rolling back anything that was changed before it failed.
+    fn on_play(&mut self, pieces: &[(Piece, i32, i32)])
+        -> Result<(), MoveError>
+    {
+        let i = self.active_player;
+        if self.must_open() {
+            let n = self.opening_size(i);
+            if pieces.len() < n {
+                return Err(MoveError::ShortOpening(n));
             }
-        } else {
-            warn!("[{}] Player {} snuck an illegal move past the first filters",
-                  self.name, player.name);
-            self.send(self.active_player, ServerMessage::MoveRejected);
         }
-    }
+        self.game.check_play(pieces)?;
 
-    fn on_swap(&mut self, pieces: &[Piece]) {
-        let player = &mut self.players[self.active_player];
-        if !player.try_remove(pieces) {
-            warn!("[{}] Player {} tried to play an unowned piece",
-                  self.name, player.name);
-            self.send(self.active_player, ServerMessage::MoveRejected);
-        } else if let Some(deal) = self.game.swap(pieces) {
-            for piece in deal.iter() {
-                *player.hand.entry(*piece).or_insert(0) += 1;
+        // Take the pieces out of the player's hand, putting them back if
+        // the board doesn't take them after all
+        let owned: Vec<Piece> = pieces.iter().map(|p| p.0).collect();
+        if !self.players[i].try_remove(&owned) {
+            return Err(MoveError::UnownedPiece);
+        }
+        let mut points = match self.game.play_scored(pieces) {
+            Ok(points) => points,
+            Err(e) => {
+                warn!("[{}] Player {} snuck an illegal move past the \
+                       first filters", self.name, self.players[i].name);
+                self.players[i].give_back(&owned);
+                return Err(e);
             }
-            self.send(self.active_player, ServerMessage::MoveAccepted(deal));
+        };
+
+        // Deal the player back up to a full hand
+        let player = &mut self.players[i];
+        let mut deal = Vec::new();
+        let n = self.game.rules.hand_size - player.hand_size();
+        for (piece, count) in self.game.deal(n) {
+            *player.hand.entry(piece).or_insert(0) += count;
+            for _i in 0..count {
+                deal.push(piece);
+            }
+        }
+        // Check whether the game is over!
+        let over = player.hand_is_empty() && self.game.bag.is_empty();
+        if over {
+            points.end_bonus = self.game.rules.end_bonus;
+        }
+        let delta = points.total();
+        player.score += delta;
+        player.breakdown += points;
 
-            // Broadcast the swap to other players
-            // This doesn't change piece count, so we don't need to broadcast
-            // PiecesRemaining to the players.
-            self.broadcast(ServerMessage::Swapped(pieces.len()));
-        } else {
-            warn!("[{}] Player {} couldn't be dealt {} pieces",
-                  self.name, player.name,
-                  pieces.len());
+        let total = player.score; // Release the borrow of player
+        self.log(Event::Played { player: i, pieces: pieces.to_vec() });
+        self.log(Event::Dealt { player: i, pieces: deal.clone() });
+        self.log(Event::Scored { player: i, delta });
+
+        // Broadcast the new score to all players
+        self.broadcast(ServerMessage::PlayerScore { delta, total });
+        self.broadcast(ServerMessage::PiecesRemaining(self.game.bag.len()));
+        self.send(i, ServerMessage::MoveAccepted(deal));
+
+        // Broadcast the play to other players
+        self.broadcast_except(i, ServerMessage::Played(pieces.to_vec()));
+
+        self.passes = 0;
+        if over {
+            self.end_game();
         }
+        Ok(())
+    }
+
+    // Swaps pieces for the active player, leaving their hand and the bag
+    // untouched if the swap can't be made
+    fn on_swap(&mut self, pieces: &[Piece]) -> Result<(), MoveError> {
+        let i = self.active_player;
+        if self.must_open() {
+            return Err(MoveError::OpeningSwap);
+        } else if pieces.is_empty() {
+            return Err(MoveError::NoPieces);
+        } else if !self.players[i].try_remove(pieces) {
+            return Err(MoveError::UnownedPiece);
+        }
+        let deal = match self.game.swap(pieces) {
+            Ok(deal) => deal,
+            Err(e) => {
+                self.players[i].give_back(pieces);
+                return Err(e);
+            }
+        };
+        self.players[i].give_back(&deal);
+        self.log(Event::Swapped {
+            player: i,
+            pieces: pieces.to_vec(),
+            dealt: deal.clone(),
+        });
+        self.passes = 0;
+        self.send(i, ServerMessage::MoveAccepted(deal));
+
+        // Broadcast the swap to other players
+        // This doesn't change piece count, so we don't need to broadcast
+        // PiecesRemaining to the players.
+        self.broadcast(ServerMessage::Swapped(pieces.len()));
+        Ok(())
     }
 
     fn on_message(&mut self, addr: SocketAddr, msg: ClientMessage) -> bool {
@@ -414,24 +1295,174 @@ impl Room {
         match msg {
             ClientMessage::Disconnected => self.on_client_disconnected(addr),
             ClientMessage::Chat(c) => {
-                let name = self.connections.get(&addr)
-                    .map_or("unknown", |i| &self.players[*i].name);
-                self.broadcast(ServerMessage::Chat{
-                            from: name.to_string(),
-                            message: c});
+                // Spectators can read the chat, but not post to it
+                if let Some(i) = self.connections.get(&addr).copied() {
+                    if self.players[i].muted {
+                        self.send(i, ServerMessage::Information(
+                            "The host has muted you".to_string()));
+                    } else {
+                        let name = self.players[i].name.clone();
+                        self.broadcast(ServerMessage::Chat{
+                                    from: name.clone(),
+                                    message: c.clone()});
+                        self.log(Event::Chat { from: name, message: c });
+                    }
+                } else {
+                    warn!("[{}] Got chat from non-player {}", self.name, addr);
+                }
             },
-            ClientMessage::CreateRoom(_) | ClientMessage::JoinRoom(_, _) => {
+            ClientMessage::CreateRoom(_) | ClientMessage::JoinRoom(_, _) |
+            ClientMessage::Rejoin(_, _) | ClientMessage::Spectate(_) => {
                 warn!("[{}] Invalid client message {:?}", self.name, msg);
             },
+            ClientMessage::AllowNameRejoin(b) => {
+                if self.is_host(addr) {
+                    self.name_rejoin = b;
+                    self.dirty = true;
+                    self.broadcast(ServerMessage::Information(
+                        if b {
+                            "Seats can now be reclaimed by name"
+                        } else {
+                            "Seats can only be reclaimed by their owner"
+                        }.to_string()));
+                } else {
+                    warn!("[{}] Non-host {} tried to change rejoin rules",
+                          self.name, addr);
+                }
+            },
+            ClientMessage::AddBot(difficulty) => {
+                if self.started {
+                    warn!("[{}] Got bot after game started", self.name);
+                } else if !self.is_host(addr) {
+                    warn!("[{}] Non-host {} tried to add a bot",
+                          self.name, addr);
+                } else if self.is_full() {
+                    self.send(self.host, ServerMessage::Information(
+                        format!("The room is full ({} seats)",
+                                self.game.rules.max_seats)));
+                } else {
+                    self.add_bot(difficulty);
+                }
+            },
+            ClientMessage::Ready(b) => {
+                if self.started {
+                    warn!("[{}] Got ready after game started", self.name);
+                } else if let Some(i) = self.connections.get(&addr).copied() {
+                    self.players[i].ready = b;
+                    self.dirty = true;
+                    self.broadcast(ServerMessage::PlayerReady(i, b));
+                } else {
+                    warn!("[{}] Invalid player {}", self.name, addr);
+                }
+            },
+            ClientMessage::SetRules(rules) => {
+                if self.started {
+                    warn!("[{}] Got rules after game started", self.name);
+                } else if !self.is_host(addr) {
+                    warn!("[{}] Non-host {} tried to change the rules",
+                          self.name, addr);
+                } else if let Err(e) = rules.is_valid() {
+                    warn!("[{}] Host asked for invalid rules {:?}",
+                          self.name, rules);
+                    self.send(self.host, ServerMessage::Information(e));
+                    self.send(self.host,
+                              ServerMessage::RulesChanged(self.game.rules));
+                } else if rules.max_seats < self.players.len() {
+                    self.send(self.host, ServerMessage::Information(format!(
+                        "There are already {} players in the room",
+                        self.players.len())));
+                    self.send(self.host,
+                              ServerMessage::RulesChanged(self.game.rules));
+                } else {
+                    self.set_rules(rules);
+                }
+            },
+            ClientMessage::SetTurnOrder(order) => {
+                if self.started {
+                    warn!("[{}] Got turn order after game started",
+                          self.name);
+                } else if !self.is_host(addr) {
+                    warn!("[{}] Non-host {} tried to change the turn order",
+                          self.name, addr);
+                } else if !self.set_turn_order(order) {
+                    warn!("[{}] Host sent an invalid turn order", self.name);
+                }
+            },
+            ClientMessage::StartGame => {
+                // Everyone who is here has to be ready
+                let waiting = self.players.iter()
+                    .filter(|p| p.ws.is_some() && !p.ready)
+                    .map(|p| p.name.clone())
+                    .collect::<Vec<_>>();
+                if self.started {
+                    warn!("[{}] Got start after game started", self.name);
+                } else if !self.is_host(addr) {
+                    warn!("[{}] Non-host {} tried to start the game",
+                          self.name, addr);
+                } else if !waiting.is_empty() {
+                    self.send(self.host, ServerMessage::Information(
+                        format!("Waiting for {} to be ready",
+                                waiting.join(", "))));
+                } else {
+                    self.start_game();
+                }
+            },
+            ClientMessage::Kick(p) => {
+                if self.can_moderate(addr, p) {
+                    self.kick(p);
+                }
+            },
+            ClientMessage::Mute(p, b) => {
+                if self.can_moderate(addr, p) {
+                    self.players[p].muted = b;
+                    self.dirty = true;
+                    self.broadcast(ServerMessage::PlayerMuted(p, b));
+                }
+            },
+            ClientMessage::TransferHost(p) => {
+                // Only someone who is here can start the game
+                if !self.can_moderate(addr, p) {
+                    // The host has already been told why
+                } else if self.players[p].ws.is_none() {
+                    self.send(self.host, ServerMessage::Information(format!(
+                        "{} can't be the host", self.players[p].name)));
+                } else {
+                    info!("[{}] Host is now '{}'", self.name,
+                          self.players[p].name);
+                    self.host = p;
+                    self.dirty = true;
+                    self.broadcast(ServerMessage::NewHost(p));
+                }
+            },
             ClientMessage::Play(pieces) => {
-                if self.ended {
-                    warn!("[{}] Got play after move ended", self.name);
+                if !self.started || self.ended {
+                    warn!("[{}] Got play outside of a game", self.name);
                 } else if let Some(i) = self.connections.get(&addr).copied() {
-                    if i == self.active_player {
-                        self.on_play(&pieces);
-                        if !self.ended {
-                            self.next_player();
-                        }
+                    if i != self.active_player {
+                        // This can happen if the turn timer runs out while
+                        // the move is on its way to the server
+                        self.reject(i, MoveError::OutOfTurn);
+                    } else if let Err(e) = self.on_play(&pieces) {
+                        // The player gets to try again
+                        self.reject(i, e);
+                    } else if !self.ended {
+                        self.next_player();
+                    }
+                } else {
+                    warn!("[{}] Invalid player {}", self.name, addr);
+                }
+            },
+            ClientMessage::Pass => {
+                if !self.started || self.ended {
+                    warn!("[{}] Got pass outside of a game", self.name);
+                } else if let Some(i) = self.connections.get(&addr).copied() {
+                    if i == self.active_player && self.must_open() {
+                        self.reject(i, MoveError::OpeningPass);
+                    } else if i == self.active_player {
+                        self.broadcast(ServerMessage::Information(
+                            format!("{} passed", self.players[i].name)));
+                        self.on_pass();
+                        self.next_player();
                     } else {
                         warn!("[{}] Player {} out of turn", self.name, addr);
                     }
@@ -440,14 +1471,15 @@ impl Room {
                 }
             },
             ClientMessage::Swap(pieces) => {
-                if self.ended {
-                    warn!("[{}] Got play after move ended", self.name);
+                if !self.started || self.ended {
+                    warn!("[{}] Got play outside of a game", self.name);
                 } else if let Some(i) = self.connections.get(&addr).copied() {
-                    if i == self.active_player {
-                        self.on_swap(&pieces);
-                        self.next_player();
+                    if i != self.active_player {
+                        self.reject(i, MoveError::OutOfTurn);
+                    } else if let Err(e) = self.on_swap(&pieces) {
+                        self.reject(i, e);
                     } else {
-                        warn!("[{}] Player {} out of turn", self.name, addr);
+                        self.next_player();
                     }
                 } else {
                     warn!("[{}] Invalid player {}", self.name, addr);
@@ -497,29 +1529,37 @@ async fn handle_connection(rooms: RoomList,
         // Try to interpret their message as joining a room
         match msg {
             ClientMessage::CreateRoom(player_name) => {
-                // Log to link address and player name
-
                 // We'll funnel all Websocket communication through one
                 // MPSC queue per room, with websockets running in their
                 // own little tasks writing to the queue.
                 let (write, read) = unbounded();
 
-                let room = Arc::new(Mutex::new(Room::default()));
+                let room = Arc::new(Mutex::new(Room {
+                    write: Some(write.clone()),
+                    game: Game::new(rand::thread_rng().gen()),
+                    ..Room::default()
+                }));
                 let handle = RoomHandle { write, room };
                 // Lock the global room list for a short time
                 let room_name = {
                     let map = &mut rooms.lock().unwrap();
                     next_room_name(map, handle.clone())
                 };
-                info!("[{}] Creating room '{}' for player {}",
-                      addr, room_name, player_name);
-                handle.room.lock().unwrap().name = room_name.clone();
+                {
+                    let room = &mut handle.room.lock().unwrap();
+                    info!("[{}] Creating room '{}' for player {} (seed {})",
+                          addr, room_name, player_name, room.game.seed);
+                    room.name = room_name.clone();
+                    room.log = Replay::new(&room_name, room.game.seed,
+                                           room.game.rules);
+                }
 
                 // To avoid spawning a new task, we'll use this task to run
                 // both the player's tx/rx queues *and* the room itself.
                 let mut h = handle.clone();
                 join(h.run_room(read),
-                     run_player(player_name, addr, handle, ws_stream)).await;
+                     run_player(player_name, None, addr, handle, ws_stream))
+                    .await;
 
                 info!("[{}] All players left, closing room.", room_name);
                 if let Err(e) = close_room.send(room_name.clone()).await {
@@ -541,20 +1581,19 @@ async fn handle_connection(rooms: RoomList,
                 // through yield points.
                 let handle = rooms.lock().unwrap().get_mut(&room_name).cloned();
 
-                // If we tried to join an existing room, then check that there
-                // are enough pieces in the bag to deal a full hand.
+                // If we tried to join an existing room, then check that
+                // there's still a seat for us.
                 if let Some(h) = handle {
-                    if !h.room.lock().unwrap().game.bag.is_empty() {
+                    let err = h.room.lock().unwrap().join_error(&name);
+                    if let Some(err) = err {
+                        let msg = ServerMessage::JoinFailed(err);
+                        let encoded = bincode::serialize(&msg)?;
+                        ws_stream.send(WebsocketMessage::Binary(encoded)).await?;
+                    } else {
                         // Happy case: add the player to the room, then switch
                         // to running the player's communication task
-                        run_player(name, addr, h, ws_stream).await;
+                        run_player(name, None, addr, h, ws_stream).await;
                         return Ok(());
-                    } else {
-                        // Not enough pieces, so report an error to the client
-                        let msg = ServerMessage::JoinFailed(
-                            "Not enough pieces left".to_string());
-                        let encoded = bincode::serialize(&msg)?;
-                        ws_stream.send(WebsocketMessage::Binary(encoded)).await?;
                     }
                 } else {
                     // Otherwise, reply that we don't know anything about that
@@ -565,6 +1604,40 @@ async fn handle_connection(rooms: RoomList,
                     ws_stream.send(WebsocketMessage::Binary(encoded)).await?;
                 }
             }
+            ClientMessage::Rejoin(room_name, token) => {
+                info!("[{}] Player sent Rejoin({})", addr, room_name);
+
+                // Look up the seat that matches this token, which must
+                // belong to a disconnected player.
+                let handle = rooms.lock().unwrap().get(&room_name).cloned();
+                let seat = handle.as_ref().and_then(|h| {
+                    let room = h.room.lock().unwrap();
+                    room.find_seat(&token)
+                        .map(|i| (i, room.players[i].name.clone()))
+                });
+                if let (Some(h), Some((i, name))) = (handle, seat) {
+                    run_player(name, Some(i), addr, h, ws_stream).await;
+                    return Ok(());
+                } else {
+                    let msg = ServerMessage::JoinFailed(
+                        format!("Could not rejoin room '{}'", room_name));
+                    let encoded = bincode::serialize(&msg)?;
+                    ws_stream.send(WebsocketMessage::Binary(encoded)).await?;
+                }
+            },
+            ClientMessage::Spectate(room_name) => {
+                info!("[{}] Sent Spectate({})", addr, room_name);
+                let handle = rooms.lock().unwrap().get(&room_name).cloned();
+                if let Some(h) = handle {
+                    run_spectator(addr, h, ws_stream).await;
+                    return Ok(());
+                } else {
+                    let msg = ServerMessage::JoinFailed(
+                        format!("Could not find room '{}'", room_name));
+                    let encoded = bincode::serialize(&msg)?;
+                    ws_stream.send(WebsocketMessage::Binary(encoded)).await?;
+                }
+            },
             // If they send an illegal message, then they obviously have ill
             // intentions and we should disconnect them right now.
             msg => {
@@ -577,6 +1650,63 @@ async fn handle_connection(rooms: RoomList,
     Ok(())
 }
 
+// Loads every room snapshot from disk, then runs each room in its own task
+fn restore_rooms(rooms: &RoomList, close_room: &UnboundedSender<String>) {
+    let entries = match fs::read_dir(&*ROOM_DIR) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    let paths = entries.filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension() == Some(OsStr::new("room")));
+    for path in paths {
+        let room = match Room::load(&path) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to load room from {}: {}", path.display(), e);
+                continue;
+            },
+        };
+        if room.ended {
+            info!("[{}] Discarding finished room", room.name);
+            if let Err(e) = fs::remove_file(&path) {
+                error!("[{}] Failed to remove snapshot: {}", room.name, e);
+            }
+            continue;
+        }
+        info!("[{}] Restored room with {} players",
+              room.name, room.players.len());
+
+        let (write, read) = unbounded();
+        let mut room = Room { write: Some(write.clone()), ..room };
+        room.on_restored();
+        let room_name = room.name.clone();
+        let handle = RoomHandle { write, room: Arc::new(Mutex::new(room)) };
+        rooms.lock().unwrap().insert(room_name.clone(), handle.clone());
+
+        let mut close_room = close_room.clone();
+        Task::spawn(async move {
+            let mut h = handle;
+            h.run_room(read).await;
+
+            info!("[{}] All players left, closing room.", room_name);
+            if let Err(e) = close_room.send(room_name.clone()).await {
+                error!("[{}] Failed to close room: {}", room_name, e);
+            }
+        }).detach();
+    }
+}
+
+// Saves a room's replay as JSON in the given directory
+fn save_replay(dir: &Path, replay: &Replay) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let start = replay.events.first().map(|e| e.0).unwrap_or_else(now_ms);
+    let path = dir.join(format!("{}-{}.json",
+                                replay.room_name.replace(' ', "-"), start));
+    fs::write(&path, replay.to_json()?)?;
+    Ok(path)
+}
+
 fn main() -> Result<(), IoError> {
     env_logger::from_env(Env::default().default_filter_or("pont_server=INFO"))
         .init();
@@ -588,21 +1718,37 @@ fn main() -> Result<(), IoError> {
 
     let rooms = RoomList::new(Mutex::new(HashMap::new()));
 
+    // Replays of closed rooms are saved into this directory
+    let replay_dir = PathBuf::from(env::var("PONT_REPLAY_DIR")
+        .unwrap_or_else(|_| "replays".to_string()));
+
     // Run a small task whose job is to close rooms when the last player leaves.
     // This task accepts room names through a MPSC queue, which all of the
     // room tasks push their names into.
     let close_room = {
-        let (tx, mut rx) = unbounded();
+        let (tx, mut rx) = unbounded::<String>();
         let rooms = rooms.clone();
         Task::spawn(async move {
             while let Some(r) = rx.next().await {
                 info!("Closing room [{}]", r);
-                rooms.lock().unwrap().remove(&r);
+                let handle = rooms.lock().unwrap().remove(&r);
+                if let Err(e) = fs::remove_file(snapshot_path(&r)) {
+                    warn!("[{}] Failed to remove snapshot: {}", r, e);
+                }
+                if let Some(h) = handle {
+                    match save_replay(&replay_dir, &h.room.lock().unwrap().log) {
+                        Ok(p) => info!("[{}] Saved replay to {}", r, p.display()),
+                        Err(e) => error!("[{}] Failed to save replay: {}", r, e),
+                    }
+                }
             }
         }).detach();
         tx
     };
 
+    // Pick up any rooms that were open when the server last stopped
+    restore_rooms(&rooms, &close_room);
+
     {   // Periodically print the number of open rooms to the logs
         let rooms = rooms.clone();
         Task::spawn(async move {
@@ -645,3 +1791,502 @@ fn main() -> Result<(), IoError> {
 
     Ok(())
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pont_common::{Color, Shape};
+    use pont_common::board::BoardGrid;
+
+    const ALICE: usize = 0;
+    const BOB: usize = 1;
+
+    // Everything that a move could change, so that we can check that a
+    // rejected move leaves it all alone
+    #[derive(Debug, PartialEq)]
+    struct Snapshot {
+        hands: Vec<HashMap<Piece, usize>>,
+        scores: Vec<u32>,
+        board: BoardGrid,
+        bag: Vec<Piece>,
+        active_player: usize,
+        turn: usize,
+        passes: usize,
+        events: usize,
+    }
+
+    fn snapshot(room: &Room) -> Snapshot {
+        Snapshot {
+            hands: room.players.iter().map(|p| p.hand.clone()).collect(),
+            scores: room.players.iter().map(|p| p.score).collect(),
+            board: room.game.board.clone(),
+            bag: room.game.bag.clone(),
+            active_player: room.active_player,
+            turn: room.turn,
+            passes: room.passes,
+            events: room.log.events.len(),
+        }
+    }
+
+    struct Seat {
+        addr: SocketAddr,
+        rx: UnboundedReceiver<ServerMessage>,
+    }
+
+    impl Seat {
+        // Returns every message that the room has sent to this seat
+        fn messages(&mut self) -> Vec<ServerMessage> {
+            let mut out = Vec::new();
+            while let Ok(Some(m)) = self.rx.try_next() {
+                out.push(m);
+            }
+            out
+        }
+    }
+
+    // Builds a room where Alice and Bob are playing, it's Alice's turn, and
+    // there's an orange cross on the board
+    fn room() -> (Room, Vec<Seat>) {
+        let mut room = Room {
+            game: Game::new(0),
+            ..Room::default()
+        };
+        let mut seats = Vec::new();
+        for (i, name) in ["alice", "bob"].iter().enumerate() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 1000 + i as u16));
+            let (tx, rx) = unbounded();
+            room.add_player(addr, name.to_string(), None, tx).unwrap();
+            seats.push(Seat { addr, rx });
+        }
+        room.start_game();
+        room.active_player = ALICE;
+
+        use Color::*;
+        use Shape::*;
+        room.players[ALICE].hand = [
+            (Clover, Orange), (Star, Orange), (Square, Orange),
+            (Cross, Orange), (Clover, Yellow), (Diamond, Red),
+        ].iter().map(|p| (*p, 1)).collect();
+        room.game.board = [((0, 0), (Cross, Orange))].iter().cloned().collect();
+
+        for s in seats.iter_mut() {
+            s.messages();
+        }
+        (room, seats)
+    }
+
+    // Sends a move that should be turned down, checking that nothing
+    // changed and that the turn didn't pass, and returns the reason
+    fn rejected(room: &mut Room, seat: &mut Seat, msg: ClientMessage)
+        -> MoveError
+    {
+        let before = snapshot(room);
+        room.on_message(seat.addr, msg);
+        assert_eq!(snapshot(room), before);
+
+        let errs = seat.messages().into_iter()
+            .filter_map(|m| match m {
+                ServerMessage::MoveRejected(e) => Some(e),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(errs.len(), 1, "Expected one rejection, got {:?}", errs);
+        errs[0]
+    }
+
+    // The parts of a room that its replay log should be able to rebuild:
+    // hands (sorted so that they can be compared), scores, board, bag, and
+    // the active player
+    type Rebuilt = (Vec<Vec<Piece>>, Vec<u32>, BoardGrid, Vec<Piece>, usize);
+
+    fn replayed(room: &Room) -> Rebuilt {
+        let state = room.log.state_at(room.log.events.len()).unwrap();
+        let mut hands = state.players.iter()
+            .map(|p| p.hand.clone())
+            .collect::<Vec<_>>();
+        for h in hands.iter_mut() {
+            h.sort();
+        }
+        (hands,
+         state.players.iter().map(|p| p.score).collect(),
+         state.game.board,
+         state.game.bag,
+         state.active_player)
+    }
+
+    fn live(room: &Room) -> Rebuilt {
+        let mut hands = room.players.iter()
+            .map(|p| hand_pieces(&p.hand))
+            .collect::<Vec<_>>();
+        for h in hands.iter_mut() {
+            h.sort();
+        }
+        (hands,
+         room.players.iter().map(|p| p.score).collect(),
+         room.game.board.clone(),
+         room.game.bag.clone(),
+         room.active_player)
+    }
+
+    // Plays a short seeded game through the room (with plays, swaps, and
+    // passes), checking after every move that rebuilding the game from the
+    // replay log gives the same hands, scores, board, and bag.
+    #[test]
+    fn replay_rebuilds_game() {
+        let rules = RuleSet::default();
+        let mut room = Room {
+            game: Game::with_rules(rules, 7),
+            log: Replay::new("test", 7, rules),
+            ..Room::default()
+        };
+        let mut seats = Vec::new();
+        for (i, name) in ["alice", "bob"].iter().enumerate() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 1000 + i as u16));
+            let (tx, rx) = unbounded();
+            room.add_player(addr, name.to_string(), None, tx).unwrap();
+            seats.push(Seat { addr, rx });
+        }
+        room.start_game();
+        assert_eq!(replayed(&room), live(&room));
+
+        for turn in 0..20 {
+            let p = room.active_player;
+            let hand = hand_pieces(&room.players[p].hand);
+            let best = Game::legal_moves(&room.game.board, &hand).into_iter()
+                .max_by_key(|m| m.len());
+            let msg = match best {
+                _ if turn % 5 == 4 => ClientMessage::Swap(hand[..2].to_vec()),
+                _ if turn % 7 == 6 => ClientMessage::Pass,
+                Some(m) => ClientMessage::Play(m),
+                None => ClientMessage::Pass,
+            };
+            room.on_message(seats[p].addr, msg);
+            assert_ne!(room.active_player, p, "Turn {} was rejected", turn);
+            assert_eq!(replayed(&room), live(&room), "After turn {}", turn);
+        }
+        assert!(room.game.board.len() > 10);
+    }
+
+    // The host is told what's wrong with rules that get turned down
+    #[test]
+    fn invalid_rules_explained() {
+        let mut room = Room { game: Game::new(0), ..Room::default() };
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let (tx, rx) = unbounded();
+        room.add_player(addr, "alice".to_string(), None, tx).unwrap();
+        let mut seat = Seat { addr, rx };
+        seat.messages();
+
+        let rules = RuleSet {
+            end_bonus: RuleSet::MAX_BONUS + 1,
+            ..RuleSet::default()
+        };
+        room.on_message(addr, ClientMessage::SetRules(rules));
+        assert_eq!(seat.messages(), vec![
+            ServerMessage::Information(
+                "Bonuses can be at most 24 points".to_string()),
+            ServerMessage::RulesChanged(RuleSet::default()),
+        ]);
+        assert_eq!(room.game.rules, RuleSet::default());
+    }
+
+    // A join that was checked before the game started can still reach
+    // add_player afterwards, so the seat has to be turned down there too
+    #[test]
+    fn no_new_seats_once_locked() {
+        let (mut room, _seats) = room();
+        assert!(room.game.rules.lock_on_start);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 2000));
+        let (tx, _rx) = unbounded();
+        assert!(room.add_player(addr, "carol".to_string(), None, tx).is_err());
+        assert_eq!(room.players.len(), 2);
+        assert!(!room.connections.contains_key(&addr));
+    }
+
+    fn play(pieces: &[(Shape, Color, i32, i32)]) -> ClientMessage {
+        ClientMessage::Play(pieces.iter()
+            .map(|(s, c, x, y)| ((*s, *c), *x, *y))
+            .collect())
+    }
+
+    #[test]
+    fn play_accepted() {
+        let (mut room, mut seats) = room();
+        let bag = room.game.bag.len();
+        room.on_message(seats[ALICE].addr,
+                        play(&[(Shape::Clover, Color::Orange, 1, 0)]));
+
+        assert!(seats[ALICE].messages().iter()
+            .any(|m| matches!(m, ServerMessage::MoveAccepted(d)
+                              if d.len() == 1)));
+        assert_eq!(room.players[ALICE].score, 2);
+        assert_eq!(room.players[ALICE].hand_size(), 6);
+        assert_eq!(room.game.bag.len(), bag - 1);
+        assert_eq!(room.game.board.len(), 2);
+        assert_eq!(room.active_player, BOB);
+    }
+
+    // Snapshots are only saved after changes, not after every message
+    #[test]
+    fn only_changes_need_saving() {
+        let (mut room, seats) = room();
+        room.dirty = false;
+        room.on_message(seats[ALICE].addr,
+                        ClientMessage::Chat("hello".to_string()));
+        room.on_turn_expired(room.timer + 1);
+        room.on_bot_turn(room.turn + 1);
+        assert!(!room.dirty);
+
+        room.on_message(seats[ALICE].addr,
+                        play(&[(Shape::Clover, Color::Orange, 1, 0)]));
+        assert!(room.dirty);
+    }
+
+    // Saves a room partway through a game, loads it back, and checks that
+    // the game carries on from the same place
+    #[test]
+    fn snapshot_round_trip() {
+        let (mut room, seats) = room();
+        room.name = "round trip".to_string();
+        room.on_message(seats[ALICE].addr,
+                        play(&[(Shape::Clover, Color::Orange, 1, 0)]));
+        let before = snapshot(&room);
+
+        let (_, data) = room.take_snapshot().unwrap();
+        assert!(!room.dirty);
+        assert!(room.take_snapshot().is_none());
+        let path = env::temp_dir()
+            .join(format!("pont-test-{}.room", std::process::id()));
+        write_snapshot(&path, &data).unwrap();
+        let mut loaded = Room::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(snapshot(&loaded), before);
+        assert_eq!(loaded.name, room.name);
+        assert_eq!(loaded.started, room.started);
+        assert_eq!(loaded.order, room.order);
+        assert_eq!(loaded.players.iter().map(|p| &p.name).collect::<Vec<_>>(),
+                   room.players.iter().map(|p| &p.name).collect::<Vec<_>>());
+        assert_eq!(loaded.players.iter().map(|p| &p.token).collect::<Vec<_>>(),
+                   room.players.iter().map(|p| &p.token).collect::<Vec<_>>());
+
+        // Nobody is connected after a restart, but the seats (and hands)
+        // are held for them
+        loaded.on_restored();
+        for (p, q) in loaded.players.iter().zip(room.players.iter()) {
+            assert!(p.can_rejoin());
+            assert_eq!(p.hand, q.hand);
+        }
+        assert_eq!(loaded.active_player, room.active_player);
+        assert_eq!(loaded.turn, room.turn);
+        assert!(loaded.dirty);
+
+        // The bag's RNG is saved too, so both games deal the same pieces
+        // from here on
+        room.game.shuffle();
+        loaded.game.shuffle();
+        assert_eq!(loaded.game.bag, room.game.bag);
+        assert_eq!(loaded.game.deal(3), room.game.deal(3));
+    }
+
+    #[test]
+    fn play_rejected_empty() {
+        let (mut room, mut seats) = room();
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], play(&[])),
+                   MoveError::NoPieces);
+    }
+
+    #[test]
+    fn play_rejected_occupied() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Clover, Color::Orange, 0, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::Occupied);
+
+        // Two pieces in the same cell are also caught
+        let msg = play(&[(Shape::Clover, Color::Orange, 1, 0),
+                         (Shape::Star, Color::Orange, 1, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::Occupied);
+    }
+
+    #[test]
+    fn play_rejected_not_linear() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Clover, Color::Orange, 1, 0),
+                         (Shape::Star, Color::Orange, 0, 1)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::NotLinear);
+    }
+
+    #[test]
+    fn play_rejected_gap() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Clover, Color::Orange, 1, 0),
+                         (Shape::Star, Color::Orange, 3, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::Gap);
+    }
+
+    #[test]
+    fn play_rejected_disconnected() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Clover, Color::Orange, 5, 5)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::Disconnected);
+    }
+
+    #[test]
+    fn play_rejected_duplicate() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Cross, Color::Orange, 1, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::DuplicateTile);
+    }
+
+    #[test]
+    fn play_rejected_mixed() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Clover, Color::Yellow, 1, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::MixedLine);
+    }
+
+    #[test]
+    fn play_rejected_unowned() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Circle, Color::Orange, 1, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::UnownedPiece);
+    }
+
+    #[test]
+    fn play_rejected_out_of_turn() {
+        let (mut room, mut seats) = room();
+        let msg = play(&[(Shape::Clover, Color::Orange, 1, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[BOB], msg),
+                   MoveError::OutOfTurn);
+    }
+
+    #[test]
+    fn play_rejected_short_opening() {
+        let (mut room, mut seats) = room();
+        room.game.rules.strict_opening = true;
+        room.game.board.clear();
+        let msg = play(&[(Shape::Clover, Color::Orange, 0, 0)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::ShortOpening(4));
+    }
+
+    #[test]
+    fn swap_accepted() {
+        let (mut room, mut seats) = room();
+        let bag = room.game.bag.clone();
+        let msg = ClientMessage::Swap(
+            vec![(Shape::Clover, Color::Orange), (Shape::Diamond, Color::Red)]);
+        room.on_message(seats[ALICE].addr, msg);
+
+        assert!(seats[ALICE].messages().iter()
+            .any(|m| matches!(m, ServerMessage::MoveAccepted(d)
+                              if d.len() == 2)));
+        assert_eq!(room.players[ALICE].hand_size(), 6);
+        assert_eq!(room.game.bag.len(), bag.len());
+        assert_ne!(room.game.bag, bag);
+        assert_eq!(room.active_player, BOB);
+    }
+
+    #[test]
+    fn swap_rejected_empty() {
+        let (mut room, mut seats) = room();
+        let msg = ClientMessage::Swap(vec![]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::NoPieces);
+    }
+
+    #[test]
+    fn swap_rejected_unowned() {
+        let (mut room, mut seats) = room();
+        let msg = ClientMessage::Swap(vec![(Shape::Circle, Color::Orange)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::UnownedPiece);
+    }
+
+    #[test]
+    fn swap_rejected_bag_too_small() {
+        let (mut room, mut seats) = room();
+        room.game.bag.truncate(1);
+        let msg = ClientMessage::Swap(
+            vec![(Shape::Clover, Color::Orange), (Shape::Diamond, Color::Red)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::BagTooSmall(1));
+    }
+
+    #[test]
+    fn swap_rejected_opening() {
+        let (mut room, mut seats) = room();
+        room.game.rules.strict_opening = true;
+        room.game.board.clear();
+        let msg = ClientMessage::Swap(vec![(Shape::Clover, Color::Orange)]);
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], msg),
+                   MoveError::OpeningSwap);
+    }
+
+    #[test]
+    fn pass_rejected_opening() {
+        let (mut room, mut seats) = room();
+        room.game.rules.strict_opening = true;
+        room.game.board.clear();
+        assert_eq!(rejected(&mut room, &mut seats[ALICE], ClientMessage::Pass),
+                   MoveError::OpeningPass);
+    }
+
+    #[test]
+    fn opening_timeout_isnt_a_pass() {
+        let (mut room, mut seats) = room();
+        room.game.rules.strict_opening = true;
+        room.game.board.clear();
+        room.turn_start = Some(Instant::now());
+        room.on_turn_expired(room.timer);
+
+        assert!(seats[ALICE].messages().contains(
+            &ServerMessage::MoveRejected(MoveError::OutOfTime)));
+        assert_eq!(room.passes, 0);
+        assert_eq!(room.active_player, BOB);
+        assert!(room.must_open());
+    }
+
+    // Passing (or timing out) with pieces left in the bag only ends the
+    // game after several rounds
+    #[test]
+    fn passing_with_pieces_in_bag() {
+        let (mut room, seats) = room();
+        assert!(!room.game.bag.is_empty());
+        for _ in 0..STALEMATE_ROUNDS * seats.len() - 1 {
+            room.on_message(seats[room.active_player].addr,
+                            ClientMessage::Pass);
+            assert!(!room.ended);
+        }
+        room.on_message(seats[room.active_player].addr, ClientMessage::Pass);
+        assert!(room.ended);
+    }
+
+    #[test]
+    fn passing_with_empty_bag() {
+        let (mut room, seats) = room();
+        room.game.bag.clear();
+        room.on_message(seats[ALICE].addr, ClientMessage::Pass);
+        assert!(!room.ended);
+        room.on_message(seats[BOB].addr, ClientMessage::Pass);
+        assert!(room.ended);
+    }
+
+    #[test]
+    fn swap_rejected_out_of_turn() {
+        let (mut room, mut seats) = room();
+        let msg = ClientMessage::Swap(vec![]);
+        assert_eq!(rejected(&mut room, &mut seats[BOB], msg),
+                   MoveError::OutOfTurn);
+    }
+}
@@ -0,0 +1,241 @@
+use std::{env, process};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use pont_common::{Game, Piece, RuleSet};
+use pont_common::strategy::{
+    Defensive, GameView, Greedy, MonteCarlo, Move, Random, Strategy};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// If nobody has managed to place a piece in this many rounds, then the game
+// is declared a stalemate and ends without a winner taking the end bonus.
+const STALEMATE_ROUNDS: usize = 3;
+
+const USAGE: &str = "\
+Usage: pont-sim [-n GAMES] [-s SEED] [-k KINDS] STRATEGY STRATEGY [STRATEGY...]
+
+Plays bot-vs-bot games and reports statistics for each seat.
+KINDS is the number of shapes and colors in the tile set (3-7, default 6).
+Strategies: random, greedy, defensive, montecarlo";
+
+fn build_strategy(name: &str, seed: u64) -> Option<Box<dyn Strategy>> {
+    match name {
+        "random" => Some(Box::new(Random::new(seed))),
+        "greedy" => Some(Box::new(Greedy)),
+        "defensive" => Some(Box::new(Defensive)),
+        "montecarlo" => Some(Box::new(MonteCarlo::new(8, seed))),
+        _ => None,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Seat {
+    strategy: Box<dyn Strategy>,
+    hand: Vec<Piece>,
+    score: u32,
+    qwirkles: usize,
+    turns: usize,
+}
+
+// Results from a single game, indexed by strategy (not by seat order)
+struct GameResult {
+    scores: Vec<u32>,
+    qwirkles: Vec<usize>,
+    turns: Vec<usize>,
+    length: usize,
+}
+
+fn draw(game: &mut Game, hand: &mut Vec<Piece>) {
+    for (piece, count) in game.deal(game.rules.hand_size - hand.len()) {
+        for _i in 0..count {
+            hand.push(piece);
+        }
+    }
+    // Keep the hand in a fixed order, since deal returns a HashMap
+    hand.sort();
+}
+
+// Plays a single game, with seats in the given order
+fn run_game(names: &[&str], order: &[usize], rules: RuleSet,
+            rng: &mut StdRng) -> GameResult
+{
+    let mut game = Game::with_rules(rules, rng.gen());
+
+    let mut seats = order.iter().map(|i| {
+        let mut hand = Vec::new();
+        draw(&mut game, &mut hand);
+        Seat {
+            strategy: build_strategy(names[*i], rng.gen()).unwrap(),
+            hand,
+            score: 0,
+            qwirkles: 0,
+            turns: 0,
+        }
+    }).collect::<Vec<_>>();
+
+    let mut length = 0;
+    let mut idle = 0;
+    let mut active = 0;
+    loop {
+        let scores = seats.iter().map(|s| s.score).collect::<Vec<_>>();
+        let seat = &mut seats[active];
+        let view = GameView {
+            rules: game.rules,
+            board: &game.board,
+            hand: &seat.hand,
+            bag_size: game.bag.len(),
+            scores: &scores,
+            player_index: active,
+        };
+        length += 1;
+        seat.turns += 1;
+
+        match seat.strategy.choose(&view) {
+            Move::Play(pieces) => {
+                for (p, _, _) in pieces.iter() {
+                    let i = seat.hand.iter().position(|q| q == p)
+                        .expect("Strategy played an unowned piece");
+                    seat.hand.swap_remove(i);
+                }
+                let points = game.play_scored(&pieces)
+                    .expect("Strategy made an illegal play");
+                seat.score += points.total();
+                seat.qwirkles += points.qwirkles as usize;
+                draw(&mut game, &mut seat.hand);
+                idle = 0;
+
+                if seat.hand.is_empty() {
+                    seat.score += game.rules.end_bonus;
+                    break;
+                }
+            },
+            Move::Swap(pieces) => {
+                for p in pieces.iter() {
+                    let i = seat.hand.iter().position(|q| q == p)
+                        .expect("Strategy swapped an unowned piece");
+                    seat.hand.swap_remove(i);
+                }
+                let dealt = game.swap(&pieces)
+                    .expect("Strategy swapped too many pieces");
+                seat.hand.extend(dealt);
+                idle += 1;
+            },
+            Move::Pass => idle += 1,
+        }
+
+        if idle >= STALEMATE_ROUNDS * seats.len() {
+            break;
+        }
+        active = (active + 1) % seats.len();
+    }
+
+    // Put results back into strategy order, rather than seat order
+    let mut out = GameResult {
+        scores: vec![0; names.len()],
+        qwirkles: vec![0; names.len()],
+        turns: vec![0; names.len()],
+        length,
+    };
+    for (seat, i) in seats.iter().zip(order.iter()) {
+        out.scores[*i] = seat.score;
+        out.qwirkles[*i] = seat.qwirkles;
+        out.turns[*i] = seat.turns;
+    }
+    out
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn main() {
+    let mut games: usize = 1000;
+    let mut seed: u64 = 0;
+    let mut rules = RuleSet::default();
+    let mut names = Vec::new();
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let mut itr = args.iter();
+    while let Some(a) = itr.next() {
+        let mut value = || itr.next().and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or_else(|| {
+                eprintln!("Invalid value for {}\n\n{}", a, USAGE);
+                process::exit(1);
+            });
+        match a.as_str() {
+            "-n" => games = value() as usize,
+            "-s" => seed = value(),
+            "-k" => rules = RuleSet::for_kinds(value() as usize),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            s if build_strategy(s, 0).is_some() => names.push(s),
+            s => {
+                eprintln!("Unknown strategy '{}'\n\n{}", s, USAGE);
+                process::exit(1);
+            },
+        }
+    }
+    if let Err(e) = rules.is_valid() {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(1);
+    }
+    if names.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+
+    let n = names.len();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut wins = vec![0.0; n];
+    let mut scores = vec![0; n];
+    let mut qwirkles = vec![0; n];
+    let mut turns = vec![0; n];
+    let mut won_length = vec![0.0; n];
+    let mut length = 0;
+    for g in 0..games {
+        // Rotate the seating order so that nobody always goes first
+        let order = (0..n).map(|i| (i + g) % n).collect::<Vec<_>>();
+        let result = run_game(&names, &order, rules, &mut rng);
+
+        // Ties split the win evenly between everyone with the top score
+        let best = *result.scores.iter().max().unwrap();
+        let winners = result.scores.iter().filter(|s| **s == best).count();
+        for i in 0..n {
+            if result.scores[i] == best {
+                wins[i] += 1.0 / winners as f64;
+                won_length[i] += result.length as f64 / winners as f64;
+            }
+            scores[i] += result.scores[i];
+            qwirkles[i] += result.qwirkles[i];
+            turns[i] += result.turns[i];
+        }
+        length += result.length;
+    }
+
+    let games = games.max(1) as f64;
+    println!("Played {} games (seed {}, {}x{} tiles), {:.1} turns per game\n",
+             games, seed, rules.kinds, rules.kinds, length as f64 / games);
+
+    // "won in" is the average length (in turns by all seats) of the games
+    // that each strategy won, which shows whether it wins by racing to empty
+    // its hand or by outlasting the others
+    println!("{:<4} {:<12} {:>10} {:>10} {:>14} {:>11} {:>7}",
+             "seat", "strategy", "win rate", "avg score", "qwirkles/game",
+             "turns/game", "won in");
+    for i in 0..n {
+        let won_in = if wins[i] > 0.0 {
+            format!("{:.1}", won_length[i] / wins[i])
+        } else {
+            "-".to_string()
+        };
+        println!("{:<4} {:<12} {:>9.1}% {:>10.1} {:>14.2} {:>11.1} {:>7}",
+                 i, names[i],
+                 100.0 * wins[i] / games,
+                 scores[i] as f64 / games,
+                 qwirkles[i] as f64 / games,
+                 turns[i] as f64 / games,
+                 won_in);
+    }
+}